@@ -0,0 +1,45 @@
+#!/usr/bin/env bash
+set -exu
+
+# Starts or stops a local Redis Cluster of 3 masters (requires redis-server and
+# redis-cli), for the cluster test suite:
+#
+#     ./scripts/redis-cluster.sh start
+#     cargo test --features cluster -- --ignored cluster
+#     ./scripts/redis-cluster.sh stop
+
+PORTS="${REDIS_CLUSTER_PORTS:-7000 7001 7002}"
+DIR="${REDIS_CLUSTER_DIR:-/tmp/redis-stream-cluster}"
+
+case "${1:-}" in
+  start)
+    nodes=""
+    for port in ${PORTS}; do
+      mkdir -p "${DIR}/${port}"
+      redis-server --port "${port}" --dir "${DIR}/${port}" \
+        --cluster-enabled yes --cluster-config-file nodes.conf \
+        --save "" --appendonly no --daemonize yes \
+        --logfile redis.log --pidfile "${DIR}/${port}/redis.pid"
+      nodes="${nodes} 127.0.0.1:${port}"
+    done
+    for port in ${PORTS}; do
+      until redis-cli -p "${port}" ping > /dev/null 2>&1; do sleep 0.1; done
+    done
+
+    # shellcheck disable=SC2086
+    redis-cli --cluster create ${nodes} --cluster-replicas 0 --cluster-yes
+    for port in ${PORTS}; do
+      until redis-cli -p "${port}" cluster info | grep -q "cluster_state:ok"; do sleep 0.1; done
+    done
+    ;;
+  stop)
+    for port in ${PORTS}; do
+      redis-cli -p "${port}" shutdown nosave || true
+    done
+    rm -rf "${DIR}"
+    ;;
+  *)
+    echo "Usage: $0 start|stop"
+    exit 1
+    ;;
+esac
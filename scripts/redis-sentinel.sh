@@ -0,0 +1,61 @@
+#!/usr/bin/env bash
+set -exu
+
+# Starts or stops a local Redis Sentinel setup (requires redis-server and
+# redis-cli): a master on port 6380, its replica on port 6381, and 3 sentinels
+# on ports 26379 to 26381 monitoring them as `mymaster`, for the sentinel test
+# suite:
+#
+#     ./scripts/redis-sentinel.sh start
+#     cargo test -- --ignored sentinel
+#     ./scripts/redis-sentinel.sh stop
+
+MASTER_PORT="${REDIS_MASTER_PORT:-6380}"
+REPLICA_PORT="${REDIS_REPLICA_PORT:-6381}"
+SENTINEL_PORTS="${REDIS_SENTINEL_PORTS:-26379 26380 26381}"
+DIR="${REDIS_SENTINEL_DIR:-/tmp/redis-stream-sentinel}"
+
+case "${1:-}" in
+  start)
+    for port in ${MASTER_PORT} ${REPLICA_PORT}; do
+      mkdir -p "${DIR}/${port}"
+      redis-server --port "${port}" --dir "${DIR}/${port}" \
+        --save "" --appendonly no --daemonize yes \
+        --logfile redis.log --pidfile "${DIR}/${port}/redis.pid"
+    done
+    for port in ${MASTER_PORT} ${REPLICA_PORT}; do
+      until redis-cli -p "${port}" ping > /dev/null 2>&1; do sleep 0.1; done
+    done
+    redis-cli -p "${REPLICA_PORT}" replicaof 127.0.0.1 "${MASTER_PORT}"
+
+    for port in ${SENTINEL_PORTS}; do
+      mkdir -p "${DIR}/${port}"
+      cat > "${DIR}/${port}/sentinel.conf" <<CONF
+port ${port}
+dir ${DIR}/${port}
+daemonize yes
+logfile sentinel.log
+pidfile ${DIR}/${port}/sentinel.pid
+sentinel monitor mymaster 127.0.0.1 ${MASTER_PORT} 2
+sentinel down-after-milliseconds mymaster 1000
+sentinel failover-timeout mymaster 5000
+CONF
+      redis-server "${DIR}/${port}/sentinel.conf" --sentinel
+    done
+    for port in ${SENTINEL_PORTS}; do
+      until redis-cli -p "${port}" sentinel replicas mymaster 2> /dev/null | grep -q "${REPLICA_PORT}"; do
+        sleep 0.1
+      done
+    done
+    ;;
+  stop)
+    for port in ${SENTINEL_PORTS} ${MASTER_PORT} ${REPLICA_PORT}; do
+      redis-cli -p "${port}" shutdown nosave || true
+    done
+    rm -rf "${DIR}"
+    ;;
+  *)
+    echo "Usage: $0 start|stop"
+    exit 1
+    ;;
+esac
@@ -0,0 +1,978 @@
+//! Async consumer and producer built on `redis::aio` connections.
+//!
+//! Requires the `aio` feature.
+//!
+//! # Basic usage:
+//!
+//! ```
+//! use redis_stream::aio::AsyncConsumer;
+//! use redis_stream::consumer::{ConsumerOpts, Message};
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let redis_url =
+//!   std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+//!
+//! let redis = redis::Client::open(redis_url)
+//!   .expect("client")
+//!   .get_multiplexed_tokio_connection()
+//!   .await
+//!   .expect("connection");
+//!
+//! // Message handler
+//! let handler = |_id: String, message: Message| async move {
+//!   // do something
+//!   Ok(())
+//! };
+//!
+//! // Consumer config
+//! let opts = ConsumerOpts::default().group("my-group", "worker.1");
+//! let mut consumer = AsyncConsumer::init(redis, "my-stream-3", handler, opts)
+//!   .await
+//!   .expect("consumer");
+//!
+//! // Consume some messages through handler.
+//! consumer.consume().await.expect("consume messages");
+//!
+//! // Clean up redis
+//! use redis::AsyncCommands;
+//! consumer
+//!   .redis
+//!   .xgroup_destroy::<&str, &str, bool>("my-stream-3", "my-group")
+//!   .await
+//!   .expect("xgroup destroy");
+//! consumer.redis.del::<&str, bool>("my-stream-3").await.expect("del");
+//! # }
+//! ```
+//!
+//! # As a `futures::Stream`:
+//!
+//! [`deliveries`] reads the messages into a `Stream` of [`Delivery`], to be
+//! combined with the `StreamExt` adapters. The deliveries are acknowledged on
+//! a second connection, which isn't held up by the blocking reads.
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//! use redis_stream::consumer::ConsumerOpts;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let client = redis::Client::open("redis://127.0.0.1:6379").expect("client");
+//! let redis = client
+//!   .get_multiplexed_tokio_connection()
+//!   .await
+//!   .expect("connection");
+//! let ack_redis = client
+//!   .get_multiplexed_tokio_connection()
+//!   .await
+//!   .expect("connection");
+//!
+//! let opts = ConsumerOpts::default().group("my-group", "worker.1");
+//! let deliveries = redis_stream::aio::deliveries(redis, ack_redis, "my-stream", opts)
+//!   .await
+//!   .expect("deliveries");
+//!
+//! // Handle up to 10 messages concurrently
+//! deliveries
+//!   .map(|delivery| async move {
+//!     let delivery = delivery?;
+//!     println!("{}: {:?}", delivery.id(), delivery.body());
+//!     delivery.ack().await
+//!   })
+//!   .buffer_unordered(10)
+//!   .for_each(|result| async move {
+//!     if let Err(err) = result {
+//!       eprintln!("{:#}", err);
+//!     }
+//!   })
+//!   .await;
+//! # }
+//! ```
+use anyhow::{Context, Result};
+use futures::stream::{BoxStream, StreamExt};
+use redis::aio::ConnectionLike;
+use redis::streams::{StreamId, StreamReadOptions, StreamReadReply};
+use redis::{AsyncCommands, RedisResult};
+use std::collections::VecDeque;
+use std::future::Future;
+
+use crate::consumer::{dead_letter_pipeline, open_message, positions, ConsumerOpts, Message};
+use crate::encryption::Keyring;
+use crate::headers::Headers;
+
+/// Produces a new message into a Redis stream, using an async connection.
+pub async fn produce<C>(redis: &mut C, stream: &str, key_values: &[(&str, &str)]) -> Result<String>
+where
+  C: ConnectionLike + Send,
+{
+  let id = redis
+    .xadd::<&str, &str, &str, &str, String>(stream, "*", key_values)
+    .await
+    .context(format!(
+      "failed to run redis command:\n\
+       XADD {} * {}",
+      stream,
+      key_values
+        .iter()
+        .map(|(k, v)| format!("{} {}", k, v))
+        .collect::<Vec<String>>()
+        .join(" ")
+    ))?;
+  Ok(id)
+}
+
+// An async Consumer or Group Consumer, owning a `redis::aio` connection (like
+// `MultiplexedConnection` or `ConnectionManager`) and able to consume
+// messages.
+pub struct AsyncConsumer<C, F, Fut>
+where
+  C: ConnectionLike + Send,
+  F: FnMut(String, Message) -> Fut,
+  Fut: Future<Output = Result<()>>,
+{
+  pub count: Option<usize>,
+  pub dead_letter_stream: Option<String>,
+  pub group: Option<(String, String)>,
+  pub handled_messages: u32,
+  pub handler: F,
+  pub keyring: Option<Keyring>,
+  pub next_pos: String,
+  pub process_pending: bool,
+  pub redis: C,
+  pub stream: String,
+  pub timeout: usize,
+}
+
+impl<C, F, Fut> AsyncConsumer<C, F, Fut>
+where
+  C: ConnectionLike + Send,
+  F: FnMut(String, Message) -> Fut,
+  Fut: Future<Output = Result<()>>,
+{
+  /// Initializes a new `aio::AsyncConsumer`.
+  pub async fn init(mut redis: C, stream: &str, handler: F, opts: ConsumerOpts) -> Result<Self> {
+    let count = opts.count;
+    let dead_letter_stream = opts.dead_letter_stream;
+    let keyring = opts.keyring;
+    let timeout = opts.timeout;
+    let group = opts.group;
+    let create_stream_if_not_exists = opts.create_stream_if_not_exists;
+    let process_pending = opts.process_pending;
+    let start_pos = opts.start_pos;
+
+    let (group_create_pos, consumer_start_pos) = positions(&group, process_pending, start_pos);
+
+    if let Some((group_name, _)) = &group {
+      ensure_stream_and_group(
+        &mut redis,
+        stream,
+        group_name.as_ref(),
+        &group_create_pos.unwrap(),
+        create_stream_if_not_exists,
+      )
+      .await?;
+    }
+
+    Ok(AsyncConsumer {
+      count,
+      dead_letter_stream,
+      group,
+      handled_messages: 0,
+      handler,
+      keyring,
+      next_pos: consumer_start_pos,
+      process_pending,
+      redis,
+      stream: stream.to_string(),
+      timeout,
+    })
+  }
+
+  /// Handle new messages from the stream, and dispatch them to the registered
+  /// handler.
+  pub async fn consume(&mut self) -> Result<()> {
+    loop {
+      // Prepare options for XREAD
+      let mut opts = if let Some((group_name, consumer_name)) = &self.group {
+        StreamReadOptions::default()
+          .group(group_name, consumer_name)
+          .block(self.timeout)
+      } else {
+        StreamReadOptions::default().block(self.timeout)
+      };
+      if let Some(count) = self.count {
+        opts = opts.count(count);
+      }
+
+      let stream_results: StreamReadReply = self
+        .redis
+        .xread_options(&[&self.stream], &[&self.next_pos], opts)
+        .await?;
+
+      if stream_results.keys.is_empty() {
+        return Ok(());
+      }
+      let stream = &stream_results.keys[0];
+
+      if self.group.is_some() && self.process_pending && stream.ids.is_empty() {
+        // We ran out of pending results, let's switch to processing most
+        // recent.
+        self.process_pending = false;
+        self.next_pos = String::from(">");
+        continue;
+      }
+
+      // Process the results and set the next position to consume from
+      for message in &stream.ids {
+        // A failed message and the rest of the batch are read again by the
+        // next call
+        self
+          .process_message(message.id.clone(), message.map.clone())
+          .await?;
+
+        // Keep next_post if we are in a consumer-group and it's already `>`
+        if self.next_pos != ">" {
+          // or take the last id
+          self.next_pos = message.id.to_string();
+        }
+      }
+
+      return Ok(());
+    }
+  }
+
+  /// Process a message by awaiting the handler and acknowledging the
+  /// message-id to Redis if necessary. Messages which can't be decrypted or
+  /// decompressed are moved to the dead-letter stream instead.
+  async fn process_message(&mut self, id: String, message: Message) -> Result<()> {
+    let message = match open_message(self.keyring.as_ref(), &self.stream, &message)? {
+      Ok(opened) => opened.unwrap_or(message),
+      Err(reason) => {
+        return dead_letter(
+          &mut self.redis,
+          self.dead_letter_stream.as_deref(),
+          &self.stream,
+          &self.group,
+          &id,
+          &message,
+          &reason,
+        )
+        .await;
+      }
+    };
+
+    // Call handler
+    (self.handler)(id.clone(), message).await?;
+    self.handled_messages += 1;
+    // XACK if needed
+    if let Some((group_name, _)) = &self.group {
+      let _ack_count: i32 = self.redis.xack(&self.stream, group_name, &[id]).await?;
+    }
+    Ok(())
+  }
+}
+
+/// A message yielded by [`deliveries`], with its [`Headers`] separated from
+/// its body, and a handle to acknowledge it.
+pub struct Delivery<C> {
+  body: Message,
+  group: Option<String>,
+  headers: Headers,
+  id: String,
+  redis: C,
+  stream: String,
+}
+
+impl<C> Delivery<C>
+where
+  C: ConnectionLike + Clone + Send,
+{
+  /// XACK the message in the group of the consumer, on the acknowledgement
+  /// connection of [`deliveries`] (does nothing for simple consumers).
+  pub async fn ack(&self) -> Result<()> {
+    if let Some(group_name) = &self.group {
+      let mut redis = self.redis.clone();
+      let _ack_count: i32 = redis
+        .xack(&self.stream, group_name, &[&self.id])
+        .await
+        .context(format!(
+          "failed to run redis command:\n\
+           XACK {} {} {}",
+          self.stream, group_name, self.id
+        ))?;
+    }
+    Ok(())
+  }
+
+  /// The fields of the message, without the headers.
+  pub fn body(&self) -> &Message {
+    &self.body
+  }
+
+  /// The headers of the message.
+  pub fn headers(&self) -> &Headers {
+    &self.headers
+  }
+
+  /// The id of the message.
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  /// The name of the stream the message was read from.
+  pub fn stream(&self) -> &str {
+    &self.stream
+  }
+}
+
+/// A `futures::Stream` of the messages read from a Redis stream.
+pub type DeliveryStream<C> = BoxStream<'static, Result<Delivery<C>>>;
+
+// State of a `DeliveryStream` between two items.
+struct DeliveryState<C> {
+  ack_redis: C,
+  buffer: VecDeque<StreamId>,
+  count: Option<usize>,
+  dead_letter_stream: Option<String>,
+  group: Option<(String, String)>,
+  keyring: Option<Keyring>,
+  next_pos: String,
+  process_pending: bool,
+  redis: C,
+  stopped: bool,
+  stream: String,
+  timeout: usize,
+}
+
+/// Reads the messages of `stream` into a `futures::Stream` of [`Delivery`],
+/// with `XREAD`, or `XREADGROUP` if `opts` has a group.
+///
+/// Messages are read in batches of `count` messages when the buffer of the
+/// previous batch is drained, so nothing is read until the items are polled.
+/// Messages are decrypted and decompressed like for [`AsyncConsumer`], and
+/// moved to the dead-letter stream when they can't be. Deliveries are not
+/// acknowledged until [`Delivery::ack`] is called.
+///
+/// The reads block on `redis` for up to `timeout` milliseconds, and the
+/// deliveries are acknowledged on `ack_redis`: it must be a connection of its
+/// own (not a clone of the same `MultiplexedConnection`), or the acks wait
+/// behind the blocking reads.
+///
+/// Messages with invalid headers are yielded as errors (and left pending).
+/// A failed read is yielded as an error too, and ends the stream: create a new
+/// one to resume reading.
+pub async fn deliveries<C>(
+  mut redis: C,
+  ack_redis: C,
+  stream: &str,
+  opts: ConsumerOpts,
+) -> Result<DeliveryStream<C>>
+where
+  C: ConnectionLike + Clone + Send + 'static,
+{
+  let (group_create_pos, consumer_start_pos) =
+    positions(&opts.group, opts.process_pending, opts.start_pos);
+  if let Some((group_name, _)) = &opts.group {
+    ensure_stream_and_group(
+      &mut redis,
+      stream,
+      group_name.as_ref(),
+      &group_create_pos.unwrap(),
+      opts.create_stream_if_not_exists,
+    )
+    .await?;
+  }
+
+  let state = DeliveryState {
+    ack_redis,
+    buffer: VecDeque::new(),
+    count: opts.count,
+    dead_letter_stream: opts.dead_letter_stream,
+    group: opts.group,
+    keyring: opts.keyring,
+    next_pos: consumer_start_pos,
+    process_pending: opts.process_pending,
+    redis,
+    stopped: false,
+    stream: stream.to_string(),
+    timeout: opts.timeout,
+  };
+  Ok(
+    futures::stream::unfold(state, |mut state| async move {
+      if state.stopped {
+        return None;
+      }
+      let delivery = next_delivery(&mut state).await;
+      Some((delivery, state))
+    })
+    .boxed(),
+  )
+}
+
+/// Pops the next message of the buffer, reading batches until there is one.
+/// Stops the stream when the connection fails.
+async fn next_delivery<C>(state: &mut DeliveryState<C>) -> Result<Delivery<C>>
+where
+  C: ConnectionLike + Clone + Send,
+{
+  loop {
+    if let Some(StreamId { id, map }) = state.buffer.pop_front() {
+      // Keep next_post if we are in a consumer-group and it's already `>`
+      if state.next_pos != ">" {
+        // or take the last id
+        state.next_pos = id.clone();
+      }
+      let message = match open_message(state.keyring.as_ref(), &state.stream, &map)? {
+        Ok(opened) => opened.unwrap_or(map),
+        Err(reason) => {
+          let result = dead_letter(
+            &mut state.redis,
+            state.dead_letter_stream.as_deref(),
+            &state.stream,
+            &state.group,
+            &id,
+            &map,
+            &reason,
+          )
+          .await;
+          if result.is_err() {
+            state.stopped = true;
+          }
+          result?;
+          continue;
+        }
+      };
+      let (headers, body) =
+        Headers::split(&message).map_err(|err| anyhow::anyhow!("message {}: {}", id, err))?;
+      return Ok(Delivery {
+        body,
+        group: state
+          .group
+          .as_ref()
+          .map(|(group_name, _)| group_name.clone()),
+        headers,
+        id,
+        redis: state.ack_redis.clone(),
+        stream: state.stream.clone(),
+      });
+    }
+
+    // Prepare options for XREAD
+    let mut opts = if let Some((group_name, consumer_name)) = &state.group {
+      StreamReadOptions::default()
+        .group(group_name, consumer_name)
+        .block(state.timeout)
+    } else {
+      StreamReadOptions::default().block(state.timeout)
+    };
+    if let Some(count) = state.count {
+      opts = opts.count(count);
+    }
+
+    let stream_results: StreamReadReply = match state
+      .redis
+      .xread_options(&[&state.stream], &[&state.next_pos], opts)
+      .await
+    {
+      Ok(stream_results) => stream_results,
+      Err(err) => {
+        state.stopped = true;
+        return Err(err.into());
+      }
+    };
+    let ids = match stream_results.keys.into_iter().next() {
+      Some(stream) => stream.ids,
+      None => continue,
+    };
+
+    if state.group.is_some() && state.process_pending && ids.is_empty() {
+      // We ran out of pending results, let's switch to processing most
+      // recent.
+      state.process_pending = false;
+      state.next_pos = String::from(">");
+      continue;
+    }
+    state.buffer.extend(ids);
+  }
+}
+
+/// A `bb8::ManageConnection` opening `MultiplexedConnection`s with a
+/// `redis::Client`.
+///
+/// A connection broken while in use isn't noticed when it is given back to
+/// the pool: it is checked with a `PING` when pulled again, so keep the
+/// `test_on_check_out` of the pool builder enabled (its default).
+///
+/// Requires the `bb8` feature.
+#[cfg(feature = "bb8")]
+#[derive(Clone, Debug)]
+pub struct PoolManager {
+  pub client: redis::Client,
+}
+
+#[cfg(feature = "bb8")]
+impl PoolManager {
+  /// Initializes a new `aio::PoolManager`.
+  pub fn new(client: redis::Client) -> Self {
+    PoolManager { client }
+  }
+}
+
+#[cfg(feature = "bb8")]
+#[async_trait::async_trait]
+impl bb8::ManageConnection for PoolManager {
+  type Connection = redis::aio::MultiplexedConnection;
+  type Error = redis::RedisError;
+
+  async fn connect(&self) -> RedisResult<Self::Connection> {
+    self.client.get_multiplexed_tokio_connection().await
+  }
+
+  async fn is_valid(&self, redis: &mut Self::Connection) -> RedisResult<()> {
+    redis::cmd("PING").query_async(redis).await
+  }
+
+  // A `MultiplexedConnection` doesn't tell whether it has failed, see
+  // `is_valid` instead.
+  fn has_broken(&self, _redis: &mut Self::Connection) -> bool {
+    false
+  }
+}
+
+/// A connection pulled from a `bb8` pool, given back to the pool when dropped.
+/// Unlike `bb8::PooledConnection`, it is a `redis::aio::ConnectionLike`, so an
+/// [`AsyncConsumer`] can own it.
+///
+/// Requires the `bb8` feature.
+///
+/// ```no_run
+/// use redis_stream::aio::{AsyncConsumer, PoolManager, Pooled};
+/// use redis_stream::consumer::{ConsumerOpts, Message};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let client = redis::Client::open("redis://127.0.0.1:6379").expect("client");
+/// let pool = bb8::Pool::builder()
+///   .build(PoolManager::new(client))
+///   .await
+///   .expect("pool");
+///
+/// let handler = |_id: String, _message: Message| async move { Ok(()) };
+/// let opts = ConsumerOpts::default().group("my-group", "worker.1");
+/// let redis = Pooled::get(&pool).await.expect("connection");
+/// let mut consumer = AsyncConsumer::init(redis, "my-stream", handler, opts)
+///   .await
+///   .expect("consumer");
+/// tokio::spawn(async move { consumer.consume().await });
+/// # }
+/// ```
+#[cfg(feature = "bb8")]
+pub struct Pooled<M: bb8::ManageConnection>(pub bb8::PooledConnection<'static, M>);
+
+#[cfg(feature = "bb8")]
+impl<M: bb8::ManageConnection> Pooled<M> {
+  /// Pulls a connection from `pool`, waiting for one to be available.
+  pub async fn get(pool: &bb8::Pool<M>) -> Result<Self> {
+    let redis = pool
+      .get_owned()
+      .await
+      .map_err(|err| anyhow::anyhow!("failed to get a redis connection: {:?}", err))?;
+    Ok(Pooled(redis))
+  }
+}
+
+#[cfg(feature = "bb8")]
+impl<M> ConnectionLike for Pooled<M>
+where
+  M: bb8::ManageConnection,
+  M::Connection: ConnectionLike,
+{
+  fn req_packed_command<'a>(
+    &'a mut self,
+    cmd: &'a redis::Cmd,
+  ) -> redis::RedisFuture<'a, redis::Value> {
+    (*self.0).req_packed_command(cmd)
+  }
+
+  fn req_packed_commands<'a>(
+    &'a mut self,
+    cmd: &'a redis::Pipeline,
+    offset: usize,
+    count: usize,
+  ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+    (*self.0).req_packed_commands(cmd, offset, count)
+  }
+
+  fn get_db(&self) -> i64 {
+    self.0.get_db()
+  }
+}
+
+// Helpers
+
+/// Moves a message to the dead-letter stream (default:
+/// `<stream>:dead-letter`), and acknowledges it in the group (if any).
+async fn dead_letter<C>(
+  redis: &mut C,
+  dead_letter_stream: Option<&str>,
+  stream: &str,
+  group: &Option<(String, String)>,
+  id: &str,
+  message: &Message,
+  error: &str,
+) -> Result<()>
+where
+  C: ConnectionLike + Send,
+{
+  let dead_letter_stream = match dead_letter_stream {
+    Some(dead_letter_stream) => dead_letter_stream.to_string(),
+    None => format!("{}:dead-letter", stream),
+  };
+  let group_name = group.as_ref().map(|(group_name, _)| group_name.as_str());
+  dead_letter_pipeline(&dead_letter_stream, stream, group_name, id, message, error)
+    .query_async::<_, ()>(redis)
+    .await
+    .context(format!(
+      "failed to move message {} from {} to dead-letter stream {}",
+      id, stream, dead_letter_stream
+    ))?;
+  Ok(())
+}
+
+/// Create Stream and Consumer-Group if required.
+async fn ensure_stream_and_group<C>(
+  redis: &mut C,
+  stream: &str,
+  group_name: &str,
+  create_pos: &str,
+  create_stream_if_not_exists: bool,
+) -> Result<()>
+where
+  C: ConnectionLike + Send,
+{
+  let mut result: RedisResult<String> = if create_stream_if_not_exists {
+    redis
+      .xgroup_create_mkstream(stream, group_name, create_pos)
+      .await
+  } else {
+    redis.xgroup_create(stream, group_name, create_pos).await
+  };
+
+  // Ignore BUSYGROUP errors, it means the group already exists, which is fine.
+  if let Err(err) = &result {
+    if err.to_string() == "BUSYGROUP: Consumer Group name already exists" {
+      result = Ok("OK".to_string());
+    }
+  }
+
+  result.context(format!(
+    "failed to run redis command:\n\
+     XGROUP CREATE {} {} {}{}",
+    stream,
+    group_name,
+    create_pos,
+    if create_stream_if_not_exists {
+      " MKSTREAM"
+    } else {
+      ""
+    }
+  ))?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::consumer::StartPosition;
+  use crate::test_helpers::*;
+  use redis::FromRedisValue;
+
+  async fn redis_async_connection() -> redis::aio::MultiplexedConnection {
+    let redis_url =
+      std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    redis::Client::open(redis_url)
+      .expect("failed to open redis client")
+      .get_multiplexed_tokio_connection()
+      .await
+      .expect("failed to get redis connection")
+  }
+
+  #[tokio::test]
+  async fn test_produce() {
+    let mut redis = redis_async_connection().await;
+    let stream = &format!("test-stream-{}", random_string(25));
+
+    produce(&mut redis, stream, &[("temperature", "31")])
+      .await
+      .unwrap();
+    let len: usize = redis.xlen(stream).await.unwrap();
+    assert_eq!(len, 1);
+
+    delete_stream(stream);
+  }
+
+  #[tokio::test]
+  async fn test_consume() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_async_connection().await;
+
+    produce(&mut redis, stream, &[("key", "value_1")])
+      .await
+      .unwrap();
+
+    // simple consumer: it processes old messages if StartOfStream
+    {
+      let (tx, rx) = std::sync::mpsc::channel();
+      let handler = |_id: String, message: Message| {
+        let tx = tx.clone();
+        async move {
+          tx.send(message)?;
+          Ok(())
+        }
+      };
+      let opts = ConsumerOpts::default().start_pos(StartPosition::StartOfStream);
+      let mut consumer = AsyncConsumer::init(redis.clone(), stream, handler, opts)
+        .await
+        .unwrap();
+
+      consumer.consume().await.unwrap();
+      let message = rx.try_recv().unwrap();
+      let value = String::from_redis_value(message.get("key").unwrap()).unwrap();
+      assert_eq!(value, "value_1".to_string());
+    }
+
+    // consumer group: it processes and acks old messages if StartOfStream
+    {
+      let (tx, rx) = std::sync::mpsc::channel();
+      let handler = |_id: String, message: Message| {
+        let tx = tx.clone();
+        async move {
+          tx.send(message)?;
+          Ok(())
+        }
+      };
+      let opts = ConsumerOpts::default()
+        .group(group_name, consumer_name)
+        .start_pos(StartPosition::StartOfStream);
+      let mut consumer = AsyncConsumer::init(redis.clone(), stream, handler, opts)
+        .await
+        .unwrap();
+
+      consumer.consume().await.unwrap();
+      let message = rx.try_recv().unwrap();
+      let value = String::from_redis_value(message.get("key").unwrap()).unwrap();
+      assert_eq!(value, "value_1".to_string());
+      assert_eq!(consumer.handled_messages, 1);
+
+      let pending: redis::streams::StreamPendingReply =
+        redis.xpending(stream, group_name).await.unwrap();
+      assert_eq!(pending.count(), 0);
+    }
+
+    let _: bool = redis.xgroup_destroy(stream, group_name).await.unwrap();
+    delete_stream(stream);
+  }
+
+  #[tokio::test]
+  async fn test_consume_failed_handler() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_async_connection().await;
+
+    for value in &["value_1", "value_2", "value_3"] {
+      produce(&mut redis, stream, &[("key", value)])
+        .await
+        .unwrap();
+    }
+
+    // the handler fails on the second message, the first time only
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut failed = false;
+    let handler = |_id: String, message: Message| {
+      let tx = tx.clone();
+      let value = String::from_redis_value(message.get("key").unwrap()).unwrap();
+      let fail = !failed && value == "value_2";
+      failed |= fail;
+      async move {
+        if fail {
+          anyhow::bail!("failed to handle {}", value);
+        }
+        tx.send(value)?;
+        Ok(())
+      }
+    };
+    let opts = ConsumerOpts::default()
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(10);
+    let mut consumer = AsyncConsumer::init(redis.clone(), stream, handler, opts)
+      .await
+      .unwrap();
+
+    // a simple consumer reads the failed message and the rest of its batch
+    // again
+    assert!(consumer.consume().await.is_err());
+    assert_eq!(consumer.handled_messages, 1);
+    consumer.consume().await.unwrap();
+    assert_eq!(consumer.handled_messages, 3);
+    let values: Vec<String> = rx.try_iter().collect();
+    assert_eq!(values, vec!["value_1", "value_2", "value_3"]);
+
+    delete_stream(stream);
+  }
+
+  #[tokio::test]
+  async fn test_deliveries() {
+    use crate::headers::Headers;
+    use crate::producer::{Producer, ProducerOpts};
+
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_async_connection().await;
+
+    let mut redis_sync = redis_connection();
+    let mut producer = Producer::init(&mut redis_sync, stream, ProducerOpts::default());
+    for i in 0..5 {
+      let headers = Headers::new().correlation_id(&i.to_string());
+      producer
+        .produce_with_headers(&headers, &[("key", "value")])
+        .unwrap();
+    }
+
+    let opts = ConsumerOpts::default()
+      .group(group_name, consumer_name)
+      .start_pos(StartPosition::StartOfStream)
+      .count(2)
+      .timeout(10);
+    let ack_redis = redis_async_connection().await;
+    let deliveries = deliveries(redis.clone(), ack_redis, stream, opts)
+      .await
+      .unwrap();
+
+    // it reads in batches, and works with the `StreamExt` adapters
+    let correlation_ids: Vec<String> = deliveries
+      .take(3)
+      .then(|delivery| async move {
+        let delivery = delivery.unwrap();
+        assert_eq!(delivery.body().len(), 1);
+        delivery.ack().await.unwrap();
+        delivery.headers().correlation_id.clone().unwrap()
+      })
+      .collect()
+      .await;
+    assert_eq!(correlation_ids, vec!["0", "1", "2"]);
+
+    // the deliveries acked are no longer pending
+    let pending: redis::streams::StreamPendingReply =
+      redis.xpending(stream, group_name).await.unwrap();
+    assert_eq!(pending.count(), 1);
+
+    let _: bool = redis.xgroup_destroy(stream, group_name).await.unwrap();
+    delete_stream(stream);
+  }
+
+  #[tokio::test]
+  async fn test_deliveries_ack_while_reading() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_async_connection().await;
+    produce(&mut redis, stream, &[("key", "value")])
+      .await
+      .unwrap();
+
+    let opts = ConsumerOpts::default()
+      .group(group_name, consumer_name)
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(2_000);
+    let ack_redis = redis_async_connection().await;
+    let mut deliveries = deliveries(redis.clone(), ack_redis, stream, opts)
+      .await
+      .unwrap();
+    let delivery = deliveries.next().await.unwrap().unwrap();
+
+    // the ack doesn't wait for the next read, blocked on the other connection
+    let reading = tokio::spawn(async move { deliveries.next().await.map(|_| ()) });
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    tokio::time::timeout(std::time::Duration::from_secs(1), delivery.ack())
+      .await
+      .expect("ack blocked by the read")
+      .unwrap();
+    reading.abort();
+
+    let pending: redis::streams::StreamPendingReply =
+      redis.xpending(stream, group_name).await.unwrap();
+    assert_eq!(pending.count(), 0);
+
+    let _: bool = redis.xgroup_destroy(stream, group_name).await.unwrap();
+    delete_stream(stream);
+  }
+
+  #[tokio::test]
+  async fn test_deliveries_stop_on_error() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_async_connection().await;
+    let _: () = redis.set(stream, "not a stream").await.unwrap();
+
+    // a failed read is yielded, then the stream ends
+    let opts = ConsumerOpts::default().timeout(10);
+    let results: Vec<Result<Delivery<_>>> = deliveries(redis.clone(), redis.clone(), stream, opts)
+      .await
+      .unwrap()
+      .collect()
+      .await;
+    assert_eq!(results.len(), 1);
+    assert!(results[0].is_err());
+
+    delete_stream(stream);
+  }
+
+  #[cfg(feature = "bb8")]
+  #[tokio::test]
+  async fn test_pooled() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let redis_url =
+      std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let manager = PoolManager::new(redis::Client::open(redis_url).unwrap());
+    let pool = bb8::Pool::builder()
+      .max_size(1)
+      .build(manager)
+      .await
+      .unwrap();
+
+    let mut redis = Pooled::get(&pool).await.unwrap();
+    produce(&mut redis, stream, &[("key", "value_1")])
+      .await
+      .unwrap();
+    drop(redis);
+
+    // it consumes from a spawned task owning its pooled connection
+    let (tx, rx) = std::sync::mpsc::channel();
+    let handler = move |_id: String, message: Message| {
+      let tx = tx.clone();
+      async move {
+        tx.send(message)?;
+        Ok(())
+      }
+    };
+    let opts = ConsumerOpts::default().start_pos(StartPosition::StartOfStream);
+    let redis = Pooled::get(&pool).await.unwrap();
+    let mut consumer = AsyncConsumer::init(redis, stream, handler, opts)
+      .await
+      .unwrap();
+    tokio::spawn(async move { consumer.consume().await })
+      .await
+      .unwrap()
+      .unwrap();
+    let message = rx.try_recv().unwrap();
+    let value = String::from_redis_value(message.get("key").unwrap()).unwrap();
+    assert_eq!(value, "value_1".to_string());
+
+    // the connection went back to the pool with the consumer
+    assert_eq!(pool.state().idle_connections, 1);
+
+    delete_stream(stream);
+  }
+}
@@ -0,0 +1,422 @@
+//! Consumer handling a whole `XREAD`/`XREADGROUP` batch at once.
+//!
+//! Useful for bulk processing (e.g. inserting many rows into a database at
+//! once): the handler receives every message read from a stream in one call,
+//! and returns the ids to acknowledge, which are sent in a single `XACK` (the
+//! ids which aren't part of the batch are ignored).
+//!
+//! Messages are decrypted and decompressed before the handler is called, like
+//! for a [`Consumer`]: the ones which can't be are moved to the dead-letter
+//! stream instead.
+//!
+//! ```no_run
+//! use redis_stream::batch::BatchConsumer;
+//! use redis_stream::consumer::{ConsumerOpts, Message};
+//!
+//! let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+//!   .expect("client")
+//!   .get_connection()
+//!   .expect("connection");
+//!
+//! let handler = |_stream: &str, messages: &[(String, Message)]| {
+//!   // bulk insert the messages somewhere
+//!   Ok(messages.iter().map(|(id, _)| id.clone()).collect())
+//! };
+//! let opts = ConsumerOpts::default().group("my-group", "worker.1").count(500);
+//! let mut consumer = BatchConsumer::init(&mut redis, "my-stream", handler, opts).unwrap();
+//! consumer.consume().expect("consume messages");
+//! ```
+//!
+//! [`Consumer`]: ../consumer/struct.Consumer.html
+use anyhow::{Context, Result};
+use redis::streams::StreamReadReply;
+use redis::{Commands, Connection};
+use std::collections::HashSet;
+
+use crate::consumer::{
+  dead_letter, ensure_stream_and_group, open_message, positions, xread, AsConnection, ConsumerOpts,
+  Message,
+};
+use crate::encryption::Keyring;
+
+// A Consumer or Group Consumer handling messages by batches.
+pub struct BatchConsumer<F, R = Connection>
+where
+  F: FnMut(&str, &[(String, Message)]) -> Result<Vec<String>>,
+  R: AsConnection,
+{
+  pub count: Option<usize>,
+  pub dead_letter_stream: Option<String>,
+  pub group: Option<(String, String)>,
+  pub handled_messages: u32,
+  pub handler: F,
+  pub keyring: Option<Keyring>,
+  /// Position to read each stream from, in the same order as `streams`.
+  pub next_pos: Vec<String>,
+  pub process_pending: bool,
+  /// The connection, borrowed, owned or pulled from a pool.
+  pub redis: R,
+  pub streams: Vec<String>,
+  pub timeout: usize,
+  // Whether the streams are read by hash slot (Redis Cluster, see
+  // `cluster`).
+  cross_slot: bool,
+}
+
+impl<F, R> BatchConsumer<F, R>
+where
+  F: FnMut(&str, &[(String, Message)]) -> Result<Vec<String>>,
+  R: AsConnection,
+{
+  /// Initializes a new `batch::BatchConsumer`.
+  pub fn init(redis: R, stream: &str, handler: F, opts: ConsumerOpts) -> Result<Self> {
+    Self::init_multi(redis, &[stream], handler, opts)
+  }
+
+  /// Initializes a new `batch::BatchConsumer` reading from several streams at
+  /// once. The handler is called once per stream and batch.
+  pub fn init_multi(
+    mut redis: R,
+    streams: &[&str],
+    handler: F,
+    opts: ConsumerOpts,
+  ) -> Result<Self> {
+    let count = opts.count;
+    let dead_letter_stream = opts.dead_letter_stream;
+    let keyring = opts.keyring;
+    let timeout = opts.timeout;
+    let group = opts.group;
+    let create_stream_if_not_exists = opts.create_stream_if_not_exists;
+    let process_pending = opts.process_pending;
+    let start_pos = opts.start_pos;
+
+    let (group_create_pos, consumer_start_pos) = positions(&group, process_pending, start_pos);
+
+    if let Some((group_name, _)) = &group {
+      let group_create_pos = group_create_pos.unwrap();
+      for stream in streams {
+        ensure_stream_and_group(
+          redis.as_connection(),
+          stream,
+          group_name.as_ref(),
+          &group_create_pos,
+          create_stream_if_not_exists,
+        )?;
+      }
+    }
+
+    Ok(BatchConsumer {
+      count,
+      dead_letter_stream,
+      group,
+      handled_messages: 0,
+      handler,
+      keyring,
+      next_pos: vec![consumer_start_pos; streams.len()],
+      process_pending,
+      redis,
+      streams: streams.iter().map(|stream| stream.to_string()).collect(),
+      timeout,
+      cross_slot: false,
+    })
+  }
+
+  /// Read a batch of messages from the streams, and dispatch it to the
+  /// registered handler.
+  ///
+  /// The ids returned by the handler are acknowledged with a single `XACK`
+  /// (group consumers only) and counted as handled. If the handler returns an
+  /// error, nothing is acknowledged and the error is returned: simple
+  /// consumers will read the same batch again, while group consumers leave it
+  /// pending. The messages which can't be opened are moved to the dead-letter
+  /// stream once the handler succeeded, so they are moved only once.
+  pub fn consume(&mut self) -> Result<()> {
+    let stream_results: StreamReadReply = xread(
+      self.redis.as_connection(),
+      &self.streams,
+      &self.next_pos,
+      &self.group,
+      self.count,
+      self.timeout,
+      &mut self.cross_slot,
+    )?;
+
+    let mut switched_to_new = false;
+    let mut processed = 0;
+
+    for stream in stream_results.keys {
+      let index = match self.streams.iter().position(|name| name == &stream.key) {
+        Some(index) => index,
+        None => continue,
+      };
+
+      if self.group.is_some()
+        && self.process_pending
+        && self.next_pos[index] != ">"
+        && stream.ids.is_empty()
+      {
+        // We ran out of pending results for this stream, let's switch to
+        // processing most recent.
+        self.next_pos[index] = String::from(">");
+        switched_to_new = true;
+        continue;
+      }
+
+      let last_id = match stream.ids.last() {
+        Some(last) => last.id.clone(),
+        None => continue,
+      };
+      processed += stream.ids.len();
+      let mut messages = vec![];
+      let mut dead_letters = vec![];
+      for message in stream.ids {
+        match open_message(self.keyring.as_ref(), &stream.key, &message.map)? {
+          Ok(opened) => messages.push((message.id, opened.unwrap_or(message.map))),
+          Err(reason) => dead_letters.push((message, reason)),
+        }
+      }
+
+      if !messages.is_empty() {
+        self.process_batch(&stream.key, &messages)?;
+      }
+      for (message, reason) in dead_letters {
+        self.dead_letter(&stream.key, &message.id, &message.map, &reason)?;
+      }
+
+      // Keep next_post if we are in a consumer-group and it's already `>`
+      if self.next_pos[index] != ">" {
+        // or take the last id
+        self.next_pos[index] = last_id;
+      }
+    }
+
+    if switched_to_new {
+      if self.next_pos.iter().all(|pos| pos == ">") {
+        self.process_pending = false;
+      }
+      if processed == 0 {
+        return self.consume();
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Process a batch by calling the handler and acknowledging the returned
+  /// message-ids to Redis if necessary.
+  fn process_batch(&mut self, stream: &str, messages: &[(String, Message)]) -> Result<()> {
+    // Call handler
+    let returned_ids = (self.handler)(stream, messages)?;
+
+    // Only the ids of the batch count as handled, once each
+    let mut batch_ids: HashSet<&str> = messages.iter().map(|(id, _)| id.as_str()).collect();
+    let mut ids: Vec<&str> = vec![];
+    for id in &returned_ids {
+      if batch_ids.remove(id.as_str()) {
+        ids.push(id);
+      }
+    }
+    self.handled_messages += ids.len() as u32;
+
+    // XACK all the ids at once if needed
+    if let Some((group_name, _)) = &self.group {
+      if !ids.is_empty() {
+        let _ack_count: i32 = self
+          .redis
+          .as_connection()
+          .xack(stream, group_name, &ids)
+          .context(format!(
+            "failed to run redis command:\n\
+           XACK {} {} {}",
+            stream,
+            group_name,
+            ids.join(" ")
+          ))?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Moves a message which can't be opened to the dead-letter stream (and
+  /// acknowledges it if we are in a consumer-group).
+  fn dead_letter(&mut self, stream: &str, id: &str, message: &Message, error: &str) -> Result<()> {
+    let dead_letter_stream = match &self.dead_letter_stream {
+      Some(dead_letter_stream) => dead_letter_stream.clone(),
+      None => format!("{}:dead-letter", stream),
+    };
+    let group_name = self
+      .group
+      .as_ref()
+      .map(|(group_name, _)| group_name.as_str());
+    dead_letter(
+      self.redis.as_connection(),
+      &dead_letter_stream,
+      stream,
+      group_name,
+      id,
+      message,
+      error,
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::consumer::StartPosition;
+  use crate::test_helpers::*;
+  use anyhow::bail;
+  use redis::FromRedisValue;
+
+  #[test]
+  fn test_consume() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    let mut ids = vec![];
+    for i in 0..5 {
+      ids.push(crate::produce(&mut redis, stream, &[("key", &format!("value_{}", i))]).unwrap());
+    }
+
+    // simple consumer: it hands every message read to the handler at once
+    {
+      let mut batches = vec![];
+      let handler = |_stream: &str, messages: &[(String, Message)]| {
+        batches.push(messages.to_vec());
+        Ok(vec![])
+      };
+      let opts = ConsumerOpts::default()
+        .start_pos(StartPosition::StartOfStream)
+        .count(3)
+        .timeout(10);
+      let mut consumer = BatchConsumer::init(&mut redis_c, stream, handler, opts).unwrap();
+      consumer.consume().unwrap();
+      consumer.consume().unwrap();
+      drop(consumer);
+
+      assert_eq!(batches.len(), 2);
+      assert_eq!(batches[0].len(), 3);
+      assert_eq!(batches[1].len(), 2);
+      let value = String::from_redis_value(batches[1][1].1.get("key").unwrap()).unwrap();
+      assert_eq!(value, "value_4".to_string());
+    }
+
+    // consumer group: it leaves the batch pending on error
+    {
+      let handler = |_stream: &str, _messages: &[(String, Message)]| -> Result<Vec<String>> {
+        bail!("I don't ack messages")
+      };
+      let opts = ConsumerOpts::default()
+        .group(group_name, consumer_name)
+        .start_pos(StartPosition::StartOfStream)
+        .timeout(10);
+      let mut consumer = BatchConsumer::init(&mut redis_c, stream, handler, opts).unwrap();
+      assert!(consumer.consume().is_err());
+
+      let pending: redis::streams::StreamPendingReply = redis.xpending(stream, group_name).unwrap();
+      assert_eq!(pending.count(), 5);
+    }
+
+    // consumer group: it acks the returned ids only
+    {
+      let handler = |_stream: &str, messages: &[(String, Message)]| {
+        Ok(
+          messages
+            .iter()
+            .filter(|(_, message)| {
+              String::from_redis_value(message.get("key").unwrap()).unwrap() != "value_2"
+            })
+            .map(|(id, _)| id.clone())
+            .collect(),
+        )
+      };
+      let opts = ConsumerOpts::default()
+        .group(group_name, consumer_name)
+        .process_pending(true)
+        .timeout(10);
+      let mut consumer = BatchConsumer::init(&mut redis_c, stream, handler, opts).unwrap();
+      consumer.consume().unwrap();
+      assert_eq!(consumer.handled_messages, 4);
+
+      let pending: redis::streams::StreamPendingCountReply = redis
+        .xpending_count(stream, group_name, "-", "+", 10)
+        .unwrap();
+      assert_eq!(pending.ids.len(), 1);
+      assert_eq!(pending.ids[0].id, ids[2]);
+    }
+
+    // consumer group: it ignores the returned ids which aren't in the batch
+    {
+      let other_ids = vec![
+        ids[2].clone(),
+        ids[2].clone(),
+        ids[0].clone(),
+        "0-1".to_string(),
+      ];
+      let handler = |_stream: &str, _messages: &[(String, Message)]| Ok(other_ids.clone());
+      let opts = ConsumerOpts::default()
+        .group(group_name, consumer_name)
+        .process_pending(true)
+        .timeout(10);
+      let mut consumer = BatchConsumer::init(&mut redis_c, stream, handler, opts).unwrap();
+      consumer.consume().unwrap();
+      assert_eq!(consumer.handled_messages, 1);
+
+      let pending: redis::streams::StreamPendingReply = redis.xpending(stream, group_name).unwrap();
+      assert_eq!(pending.count(), 0);
+    }
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+  }
+
+  #[test]
+  fn test_dead_letter_after_handler() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let dead_letter_stream = &format!("{}:dead-letter", stream);
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    crate::produce(&mut redis, stream, &[("key", "value_1")]).unwrap();
+    crate::produce(
+      &mut redis,
+      stream,
+      &[
+        ("key", "value_2"),
+        ("_compression", "unknown"),
+        ("_compressed", "key"),
+      ],
+    )
+    .unwrap();
+
+    // the handler fails on the first read
+    let mut calls = 0;
+    let handler = |_stream: &str, messages: &[(String, Message)]| -> Result<Vec<String>> {
+      calls += 1;
+      if calls == 1 {
+        bail!("failed to handle the batch");
+      }
+      Ok(messages.iter().map(|(id, _)| id.clone()).collect())
+    };
+    let opts = ConsumerOpts::default()
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(10);
+    let mut consumer = BatchConsumer::init(&mut redis_c, stream, handler, opts).unwrap();
+
+    // so the message which can't be opened is dead-lettered once, along with
+    // the batch which succeeded
+    assert!(consumer.consume().is_err());
+    let dead_letters: usize = redis.xlen(dead_letter_stream).unwrap();
+    assert_eq!(dead_letters, 0);
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 1);
+    let dead_letters: usize = redis.xlen(dead_letter_stream).unwrap();
+    assert_eq!(dead_letters, 1);
+
+    delete_stream(stream);
+    delete_stream(dead_letter_stream);
+  }
+}
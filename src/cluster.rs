@@ -0,0 +1,182 @@
+//! Redis Cluster support.
+//!
+//! [`Consumer`], [`BatchConsumer`] and [`Producer`] work with any
+//! `redis::ConnectionLike`, including the `ClusterConnection` of the `cluster`
+//! feature. Each stream lives in the hash slot of its name, so:
+//!
+//! - a consumer reading streams from several slots reads them with one
+//!   `XREAD`/`XREADGROUP` per slot (see [`slot_groups`]), which only blocks
+//!   until one of the slots has messages. Give the streams the same hash tag
+//!   (e.g. `{orders}:created` and `{orders}:paid`) to read them at once.
+//! - the dead-letter stream of `stream` defaults to `stream:dead-letter`, which
+//!   is in the same slot when `stream` has a hash tag. Without pipelining, the
+//!   copy of a message and its acknowledgement are no longer atomic.
+//! - handlers wrapped with [`with_transaction`] require pipelining, which
+//!   `ClusterConnection` doesn't support.
+//!
+//! ```no_run
+//! # #[cfg(feature = "cluster")]
+//! # {
+//! use redis_stream::consumer::{with_stream, Consumer, ConsumerOpts, Message};
+//!
+//! let mut redis = redis::cluster::ClusterClient::open(vec!["redis://127.0.0.1:7000"])
+//!   .expect("client")
+//!   .get_connection()
+//!   .expect("connection");
+//!
+//! let handler = with_stream(|stream: &str, id: &str, _message: &Message| {
+//!   println!("{} from {}", id, stream);
+//!   Ok(())
+//! });
+//! let opts = ConsumerOpts::default().group("my-group", "worker.1");
+//! let mut consumer =
+//!   Consumer::init_multi(&mut redis, &["{orders}:created", "invoices"], handler, opts).unwrap();
+//! consumer.consume().expect("consume messages");
+//! # }
+//! ```
+//!
+//! [`Consumer`]: ../consumer/struct.Consumer.html
+//! [`BatchConsumer`]: ../batch/struct.BatchConsumer.html
+//! [`Producer`]: ../producer/struct.Producer.html
+//! [`slot_groups`]: fn.slot_groups.html
+//! [`with_transaction`]: ../consumer/fn.with_transaction.html
+
+/// Number of hash slots of a Redis Cluster.
+pub const SLOTS: u16 = 16384;
+
+/// Returns the hash slot of `key`: the CRC16 of its hash tag (the part between
+/// the first `{` and the next `}`, if not empty) or of the whole key, modulo
+/// [`SLOTS`].
+///
+/// ```
+/// use redis_stream::cluster::key_slot;
+///
+/// assert_eq!(key_slot("{orders}:created"), key_slot("orders"));
+/// ```
+pub fn key_slot(key: &str) -> u16 {
+  let key = key.as_bytes();
+  let hashed = match key.iter().position(|byte| *byte == b'{') {
+    Some(open) => match key[open + 1..].iter().position(|byte| *byte == b'}') {
+      Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+      _ => key,
+    },
+    None => key,
+  };
+  crc16(hashed) % SLOTS
+}
+
+/// Groups the indexes of `streams` by hash slot, in the order of their first
+/// stream.
+pub fn slot_groups<S: AsRef<str>>(streams: &[S]) -> Vec<Vec<usize>> {
+  let mut groups: Vec<(u16, Vec<usize>)> = vec![];
+  for (index, stream) in streams.iter().enumerate() {
+    let slot = key_slot(stream.as_ref());
+    match groups
+      .iter_mut()
+      .find(|(group_slot, _)| *group_slot == slot)
+    {
+      Some((_, indexes)) => indexes.push(index),
+      None => groups.push((slot, vec![index])),
+    }
+  }
+  groups.into_iter().map(|(_, indexes)| indexes).collect()
+}
+
+/// CRC16-CCITT (XMODEM), as used by Redis Cluster.
+fn crc16(data: &[u8]) -> u16 {
+  data.iter().fold(0, |crc, byte| {
+    (0..8).fold(crc ^ (u16::from(*byte) << 8), |crc, _| {
+      if crc & 0x8000 != 0 {
+        (crc << 1) ^ 0x1021
+      } else {
+        crc << 1
+      }
+    })
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_key_slot() {
+    // it matches `CLUSTER KEYSLOT`
+    assert_eq!(crc16(b"123456789"), 0x31c3);
+    assert_eq!(key_slot("foo"), 12182);
+    assert_eq!(key_slot("somekey"), 11058);
+
+    // it only hashes the hash tag, when not empty
+    assert_eq!(key_slot("{user1000}.following"), key_slot("user1000"));
+    assert_eq!(key_slot("foo{}{bar}"), crc16(b"foo{}{bar}") % SLOTS);
+    assert_eq!(key_slot("foo{{bar}}zap"), key_slot("{bar"));
+  }
+
+  #[test]
+  fn test_slot_groups() {
+    let streams = ["{a}:1", "b", "{a}:2", "c"];
+    let groups = slot_groups(&streams);
+    assert_eq!(groups.len(), 3);
+    assert_eq!(groups[0], vec![0, 2]);
+  }
+
+  // Requires a local cluster: `make test-cluster`
+  #[cfg(feature = "cluster")]
+  #[test]
+  #[ignore]
+  fn test_consume_cluster() {
+    use crate::consumer::{with_stream, Consumer, ConsumerOpts, Message, Outcome, StartPosition};
+    use crate::producer::{Producer, ProducerOpts};
+    use crate::test_helpers::*;
+    use redis::Commands;
+
+    let mut redis = cluster_connection();
+    let mut redis_c = cluster_connection();
+    let suffix = random_string(25);
+    let streams: Vec<String> = (0..3)
+      .map(|i| format!("test-stream-{}-{}", i, suffix))
+      .collect();
+    let streams: Vec<&str> = streams.iter().map(String::as_str).collect();
+    let group_name = &format!("test-group-{}", suffix);
+    assert!(slot_groups(&streams).len() > 1);
+
+    // it produces through the cluster
+    for stream in streams.iter().copied() {
+      let mut producer = Producer::init(&mut redis, stream, ProducerOpts::default());
+      producer.produce(&[("stream", stream)]).unwrap();
+    }
+
+    // it reads the streams of every slot, and dead-letters without pipelining
+    let mut handled = vec![];
+    let handler = with_stream(|stream: &str, _id: &str, message: &Message| {
+      handled.push(stream.to_string());
+      assert_eq!(message["stream"], redis::Value::Data(stream.into()));
+      if stream == streams[0] {
+        return Ok(Outcome::DeadLetter("rejected".to_string()));
+      }
+      Ok(Outcome::Ack)
+    });
+    let opts = ConsumerOpts::default()
+      .group(group_name, "worker.1")
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(100);
+    let mut consumer = Consumer::init_multi(&mut redis_c, &streams, handler, opts).unwrap();
+    while consumer.handled_messages < 2 {
+      consumer.consume().unwrap();
+    }
+    drop(consumer);
+    handled.sort();
+    assert_eq!(handled, streams);
+    let dead_letter_stream = format!("{}:dead-letter", streams[0]);
+    let dead_letters: usize = redis.xlen(&dead_letter_stream).unwrap();
+    assert_eq!(dead_letters, 1);
+    let pending: redis::streams::StreamPendingReply =
+      redis.xpending(streams[0], group_name).unwrap();
+    assert_eq!(pending.count(), 0);
+
+    for stream in streams.iter().copied() {
+      redis.del::<_, ()>(stream).unwrap();
+    }
+    redis.del::<_, ()>(&dead_letter_stream).unwrap();
+  }
+}
@@ -0,0 +1,348 @@
+//! Pluggable payload codecs.
+//!
+//! Requires the `serde` feature, plus one feature per built-in codec: `json`
+//! ([`Json`]), `msgpack` ([`MessagePack`]), `cbor` ([`Cbor`]) and `bincode`
+//! ([`Bincode`]).
+//!
+//! An encoded message holds two reserved fields: `_codec` with the name of the
+//! codec, and `_data` with the encoded payload. Consumers detect the codec of
+//! each message, so producers can switch codecs without stopping consumers
+//! first (see [`TypedHandler::codec`] to register custom codecs).
+//!
+//! # Basic usage:
+//!
+//! ```no_run
+//! # #[cfg(feature = "msgpack")]
+//! # {
+//! use redis_stream::codec::MessagePack;
+//! use redis_stream::producer::{Producer, ProducerOpts};
+//! use serde::Serialize;
+//!
+//! #[derive(Serialize)]
+//! struct Temperature {
+//!   sensor: String,
+//!   celsius: f64,
+//! }
+//!
+//! let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+//!   .expect("client")
+//!   .get_connection()
+//!   .expect("connection");
+//!
+//! let temperature = Temperature { sensor: "042".to_string(), celsius: 21.5 };
+//! let mut producer = Producer::init(&mut redis, "temperatures", ProducerOpts::default());
+//! producer.produce_encoded(&MessagePack, &temperature).expect("produce");
+//! # }
+//! ```
+//!
+//! [`TypedHandler::codec`]: ../typed/struct.TypedHandler.html#method.codec
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::consumer::{Message, MessageFields};
+use crate::typed::DecodeError;
+
+/// Reserved field holding the name of the codec of an encoded message.
+pub const CODEC_FIELD: &str = "_codec";
+/// Reserved field holding the payload of an encoded message.
+pub const DATA_FIELD: &str = "_data";
+
+/// Encodes and decodes message payloads.
+pub trait Codec {
+  /// Name of the codec, written in the `_codec` field.
+  fn name(&self) -> &str;
+  /// Encodes `value` into a payload.
+  fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+  /// Decodes a payload into `T`.
+  fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T>;
+}
+
+/// JSON codec (`json` feature), named `json`.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+  fn name(&self) -> &str {
+    "json"
+  }
+
+  fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(value)?)
+  }
+
+  fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+    Ok(serde_json::from_slice(data)?)
+  }
+}
+
+/// MessagePack codec (`msgpack` feature), named `msgpack`.
+///
+/// Structs are encoded as maps, so fields can be added or reordered.
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+  fn name(&self) -> &str {
+    "msgpack"
+  }
+
+  fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+    Ok(rmp_serde::to_vec_named(value)?)
+  }
+
+  fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+    Ok(rmp_serde::from_slice(data)?)
+  }
+}
+
+/// CBOR codec (`cbor` feature), named `cbor`.
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+  fn name(&self) -> &str {
+    "cbor"
+  }
+
+  fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    ciborium::ser::into_writer(value, &mut data)?;
+    Ok(data)
+  }
+
+  fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+    Ok(ciborium::de::from_reader(data)?)
+  }
+}
+
+/// bincode codec (`bincode` feature), named `bincode`.
+///
+/// The most compact, but not self-describing: producers and consumers must
+/// agree on the exact same type.
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+  fn name(&self) -> &str {
+    "bincode"
+  }
+
+  fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(value)?)
+  }
+
+  fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+    Ok(bincode::deserialize(data)?)
+  }
+}
+
+/// Encodes `value` with `codec` into the `_codec` and `_data` fields.
+pub fn encode<C: Codec, T: Serialize>(codec: &C, value: &T) -> Result<Vec<(String, Vec<u8>)>> {
+  Ok(vec![
+    (CODEC_FIELD.to_string(), codec.name().as_bytes().to_vec()),
+    (DATA_FIELD.to_string(), codec.encode(value)?),
+  ])
+}
+
+/// Whether `message` was encoded by a codec (it has a `_codec` field).
+pub fn is_encoded(message: &Message) -> bool {
+  message.contains_key(CODEC_FIELD)
+}
+
+type DecodeFn<T> = Box<dyn Fn(&[u8]) -> Result<T> + Send>;
+
+/// Decodes messages into `T`, with the codec named in their `_codec` field.
+pub struct Decoder<T> {
+  codecs: HashMap<String, DecodeFn<T>>,
+}
+
+impl<T: DeserializeOwned> Decoder<T> {
+  /// A decoder knowing the built-in codecs enabled by features.
+  pub fn new() -> Self {
+    #[allow(unused_mut)]
+    let mut decoder = Decoder {
+      codecs: HashMap::new(),
+    };
+    #[cfg(feature = "json")]
+    decoder.register(Json);
+    #[cfg(feature = "msgpack")]
+    decoder.register(MessagePack);
+    #[cfg(feature = "cbor")]
+    decoder.register(Cbor);
+    #[cfg(feature = "bincode")]
+    decoder.register(Bincode);
+    decoder
+  }
+
+  /// Adds a codec, replacing any codec with the same name.
+  pub fn register<C: Codec + Send + 'static>(&mut self, codec: C) {
+    self.codecs.insert(
+      codec.name().to_string(),
+      Box::new(move |data| codec.decode(data)),
+    );
+  }
+
+  /// Decodes `message` with the codec named in its `_codec` field.
+  pub fn decode(&self, message: &Message) -> Result<T, DecodeError> {
+    let name = message
+      .str(CODEC_FIELD)
+      .ok_or_else(|| DecodeError::new(format!("missing field `{}`", CODEC_FIELD)))?;
+    let data = message
+      .bytes(DATA_FIELD)
+      .ok_or_else(|| DecodeError::new(format!("missing field `{}`", DATA_FIELD)))?;
+    let decode = self
+      .codecs
+      .get(name)
+      .ok_or_else(|| DecodeError::new(format!("unknown codec `{}`", name)))?;
+    decode(data).map_err(|err| DecodeError::new(format!("codec `{}`: {:#}", name, err)))
+  }
+}
+
+impl<T: DeserializeOwned> Default for Decoder<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde::Deserialize;
+
+  #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+  struct Temperature {
+    sensor: String,
+    value: f64,
+  }
+
+  // A custom codec, storing the sensor and value separated by a `:`
+  struct Colon;
+
+  impl Codec for Colon {
+    fn name(&self) -> &str {
+      "colon"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+      let value = serde_json::to_value(value)?;
+      Ok(format!("{}:{}", value["sensor"].as_str().unwrap(), value["value"]).into_bytes())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+      let data = std::str::from_utf8(data)?;
+      let (sensor, value) = data.split_once(':').unwrap();
+      Ok(serde_json::from_value(
+        serde_json::json!({ "sensor": sensor, "value": value.parse::<f64>()? }),
+      )?)
+    }
+  }
+
+  #[test]
+  fn test_decoder() {
+    let temperature = Temperature {
+      sensor: "042".to_string(),
+      value: 21.5,
+    };
+    let message = |fields: Vec<(String, Vec<u8>)>| -> Message {
+      fields
+        .into_iter()
+        .map(|(k, v)| (k, redis::Value::Data(v)))
+        .collect()
+    };
+
+    // it round-trips with the enabled codecs
+    let decoder = Decoder::<Temperature>::new();
+    #[cfg(feature = "json")]
+    assert_eq!(
+      decoder.decode(&message(encode(&Json, &temperature).unwrap())),
+      Ok(temperature.clone())
+    );
+    #[cfg(feature = "msgpack")]
+    assert_eq!(
+      decoder.decode(&message(encode(&MessagePack, &temperature).unwrap())),
+      Ok(temperature.clone())
+    );
+    #[cfg(feature = "cbor")]
+    assert_eq!(
+      decoder.decode(&message(encode(&Cbor, &temperature).unwrap())),
+      Ok(temperature.clone())
+    );
+    #[cfg(feature = "bincode")]
+    assert_eq!(
+      decoder.decode(&message(encode(&Bincode, &temperature).unwrap())),
+      Ok(temperature.clone())
+    );
+
+    // it fails on unknown codecs, until registered
+    let colon = message(encode(&Colon, &temperature).unwrap());
+    assert_eq!(
+      decoder.decode(&colon).unwrap_err().to_string(),
+      "failed to decode message: unknown codec `colon`"
+    );
+    let mut decoder = decoder;
+    decoder.register(Colon);
+    assert_eq!(decoder.decode(&colon), Ok(temperature));
+  }
+
+  #[cfg(all(feature = "json", feature = "msgpack"))]
+  #[test]
+  fn test_consume_mixed_codecs() {
+    use crate::consumer::{ConsumerOpts, StartPosition};
+    use crate::producer::{Producer, ProducerOpts};
+    use crate::test_helpers::*;
+    use crate::typed::TypedConsumer;
+
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    // producers migrating from flat fields to JSON, then to MessagePack
+    let temperature = |value: f64| Temperature {
+      sensor: "042".to_string(),
+      value,
+    };
+    crate::produce_typed(
+      &mut redis,
+      stream,
+      &temperature(1.0),
+      &crate::typed::Layout::Flatten,
+    )
+    .unwrap();
+    let mut producer = Producer::init(&mut redis, stream, ProducerOpts::default());
+    producer.produce_encoded(&Json, &temperature(2.0)).unwrap();
+    producer
+      .produce_encoded(&MessagePack, &temperature(3.0))
+      .unwrap();
+
+    let mut temperatures = vec![];
+    let handler = |_id: &str, temperature: Temperature| {
+      temperatures.push(temperature);
+      Ok(())
+    };
+    let opts = ConsumerOpts::default()
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(10);
+    let mut consumer = TypedConsumer::init_typed(&mut redis_c, stream, handler, opts).unwrap();
+
+    // it detects the codec of each message
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 3);
+    drop(consumer);
+    assert_eq!(
+      temperatures,
+      vec![temperature(1.0), temperature(2.0), temperature(3.0)]
+    );
+
+    delete_stream(stream);
+  }
+}
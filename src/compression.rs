@@ -0,0 +1,299 @@
+//! Transparent compression of field values.
+//!
+//! Each algorithm requires its own feature: `gzip`, `lz4` or `zstd`.
+//!
+//! A [`Producer`] with a [`compression`] compresses the field values at least
+//! [`compression_threshold`] bytes long, and adds two marker fields:
+//! `_compression` with the name of the algorithm, and `_compressed` with the
+//! comma separated names of the compressed fields. [`Consumer`]s decompress
+//! them before calling the handler.
+//!
+//! ```no_run
+//! # #[cfg(feature = "zstd")]
+//! # {
+//! use redis_stream::compression::Compression;
+//! use redis_stream::producer::{Producer, ProducerOpts};
+//!
+//! let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+//!   .expect("client")
+//!   .get_connection()
+//!   .expect("connection");
+//!
+//! let opts = ProducerOpts::default().compression(Compression::Zstd(3));
+//! let mut producer = Producer::init(&mut redis, "logs", opts);
+//! producer.produce(&[("line", "a very long log line")]).expect("produce");
+//! # }
+//! ```
+//!
+//! [`Producer`]: ../producer/struct.Producer.html
+//! [`compression`]: ../types/struct.ProducerOpts.html#method.compression
+//! [`compression_threshold`]: ../types/struct.ProducerOpts.html#method.compression_threshold
+//! [`Consumer`]: ../consumer/struct.Consumer.html
+use anyhow::{bail, Context, Result};
+use redis::Value;
+
+use crate::consumer::{Message, MessageFields};
+
+/// Reserved field holding the name of the compression algorithm.
+pub const COMPRESSION_FIELD: &str = "_compression";
+/// Reserved field holding the comma separated names of the compressed fields.
+pub const COMPRESSED_FIELD: &str = "_compressed";
+/// Maximum size of a decompressed field value (64 MiB): values decompressing
+/// to more fail, and their message is moved to the dead-letter stream.
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// Compression algorithm, with its level when it has one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+  /// gzip (`gzip` feature), with a level from 0 to 9.
+  #[cfg(feature = "gzip")]
+  Gzip(u32),
+  /// LZ4 (`lz4` feature), the fastest.
+  #[cfg(feature = "lz4")]
+  Lz4,
+  /// Zstandard (`zstd` feature), with a level from 1 to 22.
+  #[cfg(feature = "zstd")]
+  Zstd(i32),
+}
+
+impl Compression {
+  /// Name of the algorithm, written in the `_compression` field.
+  pub fn name(&self) -> &'static str {
+    match *self {
+      #[cfg(feature = "gzip")]
+      Compression::Gzip(_) => "gzip",
+      #[cfg(feature = "lz4")]
+      Compression::Lz4 => "lz4",
+      #[cfg(feature = "zstd")]
+      Compression::Zstd(_) => "zstd",
+    }
+  }
+
+  /// Compresses `data`.
+  #[cfg_attr(
+    not(any(feature = "gzip", feature = "lz4", feature = "zstd")),
+    allow(unused_variables)
+  )]
+  pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+    match *self {
+      #[cfg(feature = "gzip")]
+      Compression::Gzip(level) => {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::new(level));
+        encoder.write_all(data)?;
+        Ok(encoder.finish()?)
+      }
+      #[cfg(feature = "lz4")]
+      Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+      #[cfg(feature = "zstd")]
+      Compression::Zstd(level) => Ok(zstd::encode_all(data, level)?),
+    }
+  }
+}
+
+/// Decompresses `data` compressed with the algorithm `name`, failing if it
+/// decompresses to more than `limit` bytes.
+#[cfg_attr(
+  not(any(feature = "gzip", feature = "lz4", feature = "zstd")),
+  allow(unused_variables)
+)]
+pub fn decompress(name: &str, data: &[u8], limit: usize) -> Result<Vec<u8>> {
+  match name {
+    #[cfg(feature = "gzip")]
+    "gzip" => read_at_most(flate2::read::GzDecoder::new(data), limit),
+    #[cfg(feature = "lz4")]
+    "lz4" => {
+      // the decompressed size is prepended, and allocated upfront
+      let size = data
+        .get(..4)
+        .map(|size| u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize)
+        .context("missing lz4 decompressed size")?;
+      if size > limit {
+        bail!("decompressed size above the limit of {} bytes", limit);
+      }
+      Ok(lz4_flex::decompress_size_prepended(data)?)
+    }
+    #[cfg(feature = "zstd")]
+    "zstd" => read_at_most(zstd::stream::read::Decoder::new(data)?, limit),
+    _ if ["gzip", "lz4", "zstd"].contains(&name) => bail!(
+      "unsupported compression `{}` (requires the `{}` feature)",
+      name,
+      name
+    ),
+    _ => bail!("unknown compression `{}`", name),
+  }
+}
+
+/// Reads `reader` to the end, failing as soon as more than `limit` bytes are
+/// read rather than allocating them all.
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+fn read_at_most<R: std::io::Read>(reader: R, limit: usize) -> Result<Vec<u8>> {
+  use std::io::Read;
+  let mut decompressed = vec![];
+  reader
+    .take(limit as u64 + 1)
+    .read_to_end(&mut decompressed)?;
+  if decompressed.len() > limit {
+    bail!("decompressed size above the limit of {} bytes", limit);
+  }
+  Ok(decompressed)
+}
+
+/// Returns a copy of `message` with its compressed fields decompressed and
+/// the marker fields removed, or `None` if it isn't compressed.
+///
+/// Each field decompresses to at most [`MAX_DECOMPRESSED_SIZE`] bytes, so a
+/// small message can't exhaust the memory of the consumer.
+pub fn decompress_message(message: &Message) -> Result<Option<Message>> {
+  let name = match message.get(COMPRESSION_FIELD) {
+    Some(_) => message
+      .str(COMPRESSION_FIELD)
+      .context("invalid `_compression` field")?,
+    None => return Ok(None),
+  };
+  let fields = message.str(COMPRESSED_FIELD).unwrap_or("");
+
+  let mut decompressed = message.clone();
+  decompressed.remove(COMPRESSION_FIELD);
+  decompressed.remove(COMPRESSED_FIELD);
+  for field in fields.split(',').filter(|field| !field.is_empty()) {
+    let data = message
+      .bytes(field)
+      .with_context(|| format!("missing compressed field `{}`", field))?;
+    let data = decompress(name, data, MAX_DECOMPRESSED_SIZE)
+      .with_context(|| format!("failed to decompress field `{}` with {}", field, name))?;
+    decompressed.insert(field.to_string(), Value::Data(data));
+  }
+
+  Ok(Some(decompressed))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::consumer::{Consumer, ConsumerOpts, StartPosition};
+  use crate::producer::{Producer, ProducerOpts};
+  use crate::test_helpers::*;
+  use redis::Commands;
+
+  fn compressions() -> Vec<Compression> {
+    vec![
+      #[cfg(feature = "gzip")]
+      Compression::Gzip(6),
+      #[cfg(feature = "lz4")]
+      Compression::Lz4,
+      #[cfg(feature = "zstd")]
+      Compression::Zstd(3),
+    ]
+  }
+
+  #[test]
+  fn test_decompress_message() {
+    // it leaves uncompressed messages alone
+    let mut message = Message::new();
+    message.insert("key".to_string(), Value::Data(b"value".to_vec()));
+    assert_eq!(decompress_message(&message).unwrap(), None);
+
+    // it fails on unknown algorithms
+    message.insert(COMPRESSION_FIELD.to_string(), Value::Data(b"xz".to_vec()));
+    message.insert(COMPRESSED_FIELD.to_string(), Value::Data(b"key".to_vec()));
+    assert_eq!(
+      format!("{:#}", decompress_message(&message).unwrap_err()),
+      "failed to decompress field `key` with xz: unknown compression `xz`"
+    );
+  }
+
+  #[test]
+  fn test_decompress_limit() {
+    let data = vec![0; 1000];
+    for compression in compressions() {
+      let compressed = compression.compress(&data).unwrap();
+
+      // it decompresses up to the limit
+      assert_eq!(
+        decompress(compression.name(), &compressed, 1000).unwrap(),
+        data
+      );
+
+      // but not above it
+      assert_eq!(
+        format!(
+          "{:#}",
+          decompress(compression.name(), &compressed, 999).unwrap_err()
+        ),
+        "decompressed size above the limit of 999 bytes"
+      );
+    }
+  }
+
+  #[test]
+  fn test_consume_compressed() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let dead_letter_stream = &format!("{}:dead-letter", stream);
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+    let long_value = "log line ".repeat(100);
+
+    for compression in compressions() {
+      let opts = ProducerOpts::default()
+        .compression(compression)
+        .compression_threshold(100);
+      let mut producer = Producer::init(&mut redis, stream, opts);
+      producer
+        .produce(&[("short", "value"), ("long", &long_value)])
+        .unwrap();
+
+      // it only compresses the values above the threshold
+      let entries: redis::streams::StreamRangeReply = redis.xrange_all(stream).unwrap();
+      let raw = &entries.ids[0].map;
+      assert_eq!(raw.str(COMPRESSION_FIELD), Some(compression.name()));
+      assert_eq!(raw.str(COMPRESSED_FIELD), Some("long"));
+      assert_eq!(raw.str("short"), Some("value"));
+      assert!(raw.bytes("long").unwrap().len() < long_value.len());
+
+      // it decompresses before calling the handler
+      let mut messages = vec![];
+      let handler = |_id: &str, message: &Message| {
+        messages.push(message.clone());
+        Ok(())
+      };
+      let opts = ConsumerOpts::default()
+        .start_pos(StartPosition::StartOfStream)
+        .timeout(10);
+      let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+      consumer.consume().unwrap();
+      drop(consumer);
+      assert_eq!(messages.len(), 1);
+      assert_eq!(messages[0].str("long"), Some(long_value.as_str()));
+      assert_eq!(messages[0].str("short"), Some("value"));
+      assert!(!messages[0].contains_key(COMPRESSION_FIELD));
+      assert!(!messages[0].contains_key(COMPRESSED_FIELD));
+
+      delete_stream(stream);
+    }
+
+    // it dead-letters messages failing to decompress
+    crate::produce(
+      &mut redis,
+      stream,
+      &[
+        ("long", "not compressed"),
+        (COMPRESSION_FIELD, "lz4"),
+        (COMPRESSED_FIELD, "long"),
+      ],
+    )
+    .unwrap();
+    let handler = |_id: &str, _message: &Message| Ok(());
+    let opts = ConsumerOpts::default()
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(10);
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 0);
+    let len: usize = redis.xlen(dead_letter_stream).unwrap();
+    assert_eq!(len, 1);
+
+    delete_stream(stream);
+    delete_stream(dead_letter_stream);
+  }
+}
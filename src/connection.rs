@@ -0,0 +1,369 @@
+//! Connections used by consumers and producers.
+//!
+//! A [`Consumer`], [`BatchConsumer`] or [`Producer`] either borrows its
+//! connection (`&mut Connection`), owns it (`Connection`), or holds a
+//! connection pulled from an `r2d2` pool (`r2d2` feature): see
+//! [`AsConnection`]. An owned or pooled connection makes the consumer
+//! `Send + 'static`, so it can be stored in a struct or moved into a thread.
+//!
+//! ```no_run
+//! use redis_stream::consumer::{Consumer, ConsumerOpts, Message};
+//!
+//! let redis = redis::Client::open("redis://127.0.0.1:6379")
+//!   .expect("client")
+//!   .get_connection()
+//!   .expect("connection");
+//!
+//! let handler = |_id: &str, _message: &Message| Ok(());
+//! let opts = ConsumerOpts::default().group("my-group", "worker.1");
+//! let mut consumer = Consumer::init(redis, "my-stream", handler, opts).unwrap();
+//!
+//! let worker = std::thread::spawn(move || consumer.consume());
+//! worker.join().unwrap().expect("consume messages");
+//! ```
+//!
+//! With a pool, each consumer holds one of its connections until dropped:
+//!
+//! ```no_run
+//! # #[cfg(feature = "r2d2")]
+//! # {
+//! use redis_stream::consumer::{Consumer, ConsumerOpts, Message};
+//!
+//! let client = redis::Client::open("redis://127.0.0.1:6379").expect("client");
+//! let pool = r2d2::Pool::builder().max_size(4).build(client).expect("pool");
+//!
+//! let handler = |_id: &str, _message: &Message| Ok(());
+//! let opts = ConsumerOpts::default().group("my-group", "worker.1");
+//! let redis = pool.get().expect("connection");
+//! let mut consumer = Consumer::init(redis, "my-stream", handler, opts).unwrap();
+//! consumer.consume().expect("consume messages");
+//! # }
+//! ```
+//!
+//! [`Consumer`]: ../consumer/struct.Consumer.html
+//! [`BatchConsumer`]: ../batch/struct.BatchConsumer.html
+//! [`Producer`]: ../producer/struct.Producer.html
+use anyhow::{Context, Result};
+use redis::{Connection, ConnectionLike, ErrorKind, RedisResult};
+
+use crate::types::{Backoff, Endpoint};
+
+/// A connection borrowed, owned or pooled by a consumer or a producer.
+///
+/// Any `redis::ConnectionLike` implementing [`Reconnect`] can be borrowed. To
+/// be owned, a connection of another type implements `AsConnection` too:
+///
+/// ```
+/// # use redis::{ConnectionLike, RedisResult, Value};
+/// # struct MyConnection;
+/// # impl ConnectionLike for MyConnection {
+/// #   fn req_packed_command(&mut self, _cmd: &[u8]) -> RedisResult<Value> { unimplemented!() }
+/// #   fn req_packed_commands(&mut self, _cmd: &[u8], _offset: usize, _count: usize) -> RedisResult<Vec<Value>> { unimplemented!() }
+/// #   fn get_db(&self) -> i64 { 0 }
+/// #   fn check_connection(&mut self) -> bool { true }
+/// #   fn is_open(&self) -> bool { true }
+/// # }
+/// use redis_stream::connection::{AsConnection, Reconnect};
+///
+/// impl Reconnect for MyConnection {}
+///
+/// impl AsConnection for MyConnection {
+///   type Connection = Self;
+///
+///   fn as_connection(&mut self) -> &mut Self {
+///     self
+///   }
+/// }
+/// ```
+pub trait AsConnection {
+  /// The underlying connection.
+  type Connection: ConnectionLike + Reconnect;
+
+  /// Borrows the underlying connection.
+  fn as_connection(&mut self) -> &mut Self::Connection;
+}
+
+impl<C> AsConnection for &mut C
+where
+  C: ConnectionLike + Reconnect,
+{
+  type Connection = C;
+
+  fn as_connection(&mut self) -> &mut C {
+    self
+  }
+}
+
+impl AsConnection for Connection {
+  type Connection = Connection;
+
+  fn as_connection(&mut self) -> &mut Connection {
+    self
+  }
+}
+
+#[cfg(feature = "cluster")]
+impl AsConnection for redis::cluster::ClusterConnection {
+  type Connection = redis::cluster::ClusterConnection;
+
+  fn as_connection(&mut self) -> &mut redis::cluster::ClusterConnection {
+    self
+  }
+}
+
+/// A connection pulled from an `r2d2` pool (of `redis::Client` or
+/// `redis::cluster::ClusterClient`), given back to the pool when dropped.
+///
+/// Requires the `r2d2` feature.
+#[cfg(feature = "r2d2")]
+impl<M> AsConnection for r2d2::PooledConnection<M>
+where
+  M: r2d2::ManageConnection,
+  M::Connection: ConnectionLike + Reconnect,
+{
+  type Connection = M::Connection;
+
+  fn as_connection(&mut self) -> &mut M::Connection {
+    self
+  }
+}
+
+/// A connection which can be reopened by a [`Consumer`] or a `Producer` after
+/// it was lost, see [`ConsumerOpts::reconnect`].
+///
+/// It is only used with `reconnect`, so another `redis::ConnectionLike` can
+/// opt in with an empty impl: the connection is then expected to reconnect
+/// by itself, like a `ClusterConnection`.
+///
+/// ```
+/// # struct MyConnection;
+/// impl redis_stream::connection::Reconnect for MyConnection {}
+/// ```
+///
+/// [`Consumer`]: ../consumer/struct.Consumer.html
+/// [`ConsumerOpts::reconnect`]: ../types/struct.ConsumerOpts.html#method.reconnect
+pub trait Reconnect {
+  /// Reopens the connection to `endpoint` (does nothing by default, so only
+  /// the positions of the consumer are reset).
+  fn reconnect(&mut self, _endpoint: &Endpoint) -> RedisResult<()> {
+    Ok(())
+  }
+}
+
+impl Reconnect for Connection {
+  fn reconnect(&mut self, endpoint: &Endpoint) -> RedisResult<()> {
+    *self = endpoint.get_connection()?;
+    Ok(())
+  }
+}
+
+/// A `ClusterConnection` reconnects to the nodes of the cluster by itself, so
+/// only the positions of the consumer are reset and `endpoint` is ignored.
+#[cfg(feature = "cluster")]
+impl Reconnect for redis::cluster::ClusterConnection {}
+
+/// Whether `err` comes from a lost connection to Redis, or from a master
+/// demoted to a replica by a failover (writes are refused, and blocked reads
+/// interrupted).
+pub(crate) fn is_connection_lost(err: &redis::RedisError) -> bool {
+  err.is_io_error()
+    || err.is_connection_dropped()
+    || err.is_connection_refusal()
+    || err.kind() == ErrorKind::ReadOnly
+    || err.code() == Some("UNBLOCKED")
+}
+
+/// Reopens the connection to `endpoint`, waiting between attempts according to
+/// `backoff`.
+pub(crate) fn reconnect<C: Reconnect>(
+  redis: &mut C,
+  endpoint: &Endpoint,
+  backoff: &Backoff,
+) -> Result<()> {
+  let mut attempt = 0;
+  loop {
+    match redis.reconnect(endpoint) {
+      Ok(()) => return Ok(()),
+      Err(err) => {
+        attempt += 1;
+        if backoff
+          .max_attempts
+          .is_some_and(|max_attempts| attempt >= max_attempts)
+        {
+          return Err(err).context(format!(
+            "failed to reconnect to redis after {} attempts",
+            attempt
+          ));
+        }
+        std::thread::sleep(backoff.delay(attempt));
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::consumer::{Consumer, ConsumerOpts, Message, StartPosition};
+  use crate::producer::{Producer, ProducerOpts};
+  use crate::test_helpers::*;
+  use redis::{FromRedisValue, Value};
+  use std::sync::mpsc;
+
+  // A connection of another type, counting the commands it runs.
+  struct CountingConnection {
+    commands: usize,
+    redis: Connection,
+  }
+
+  impl ConnectionLike for CountingConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+      self.commands += 1;
+      self.redis.req_packed_command(cmd)
+    }
+
+    fn req_packed_commands(
+      &mut self,
+      cmd: &[u8],
+      offset: usize,
+      count: usize,
+    ) -> RedisResult<Vec<Value>> {
+      self.commands += 1;
+      self.redis.req_packed_commands(cmd, offset, count)
+    }
+
+    fn get_db(&self) -> i64 {
+      self.redis.get_db()
+    }
+
+    fn check_connection(&mut self) -> bool {
+      self.redis.check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+      self.redis.is_open()
+    }
+  }
+
+  impl Reconnect for CountingConnection {}
+
+  #[test]
+  fn test_other_connection() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = CountingConnection {
+      commands: 0,
+      redis: redis_connection(),
+    };
+
+    // it produces and consumes through any connection opting in to Reconnect
+    crate::produce(&mut redis, stream, &[("key", "value")]).unwrap();
+    let handler = |_id: &str, _message: &Message| Ok(());
+    let opts = ConsumerOpts::default()
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(100);
+    let mut consumer = Consumer::init(&mut redis, stream, handler, opts).unwrap();
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 1);
+    drop(consumer);
+    assert_eq!(redis.commands, 2);
+
+    delete_stream(stream);
+  }
+
+  impl AsConnection for CountingConnection {
+    type Connection = Self;
+
+    fn as_connection(&mut self) -> &mut Self {
+      self
+    }
+  }
+
+  #[test]
+  fn test_owned_connection() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let group_name = &format!("test-group-{}", random_string(25));
+
+    // it produces and consumes through connections they own
+    let mut producer = Producer::init(redis_connection(), stream, ProducerOpts::default());
+    producer.produce(&[("key", "value")]).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let handler = move |_id: &str, message: &Message| {
+      tx.send(String::from_redis_value(&message["key"])?)?;
+      Ok(())
+    };
+    let opts = ConsumerOpts::default()
+      .group(group_name, "worker.1")
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(100);
+    let mut consumer = Consumer::init(redis_connection(), stream, handler, opts).unwrap();
+
+    // so they can be moved into a thread
+    let worker = std::thread::spawn(move || {
+      consumer.consume().unwrap();
+      consumer
+    });
+    let consumer = worker.join().unwrap();
+    assert_eq!(consumer.handled_messages, 1);
+    assert_eq!(rx.try_recv().unwrap(), "value");
+
+    // and so do connections of other types
+    let redis = CountingConnection {
+      commands: 0,
+      redis: redis_connection(),
+    };
+    let handler = |_id: &str, _message: &Message| Ok(());
+    let opts = ConsumerOpts::default()
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(100);
+    let mut consumer = Consumer::init(redis, stream, handler, opts).unwrap();
+    let worker = std::thread::spawn(move || {
+      consumer.consume().unwrap();
+      consumer
+    });
+    let consumer = worker.join().unwrap();
+    assert_eq!(consumer.handled_messages, 1);
+    assert_eq!(consumer.redis.commands, 1);
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+  }
+
+  #[cfg(feature = "cluster")]
+  #[test]
+  fn test_owned_cluster_connection() {
+    fn assert_send_static<T: Send + 'static>() {}
+    type Handler = fn(&str, &Message) -> anyhow::Result<()>;
+
+    // a consumer owning a cluster connection can be moved into a thread too
+    assert_send_static::<Consumer<Handler, redis::cluster::ClusterConnection>>();
+    assert_send_static::<Producer<redis::cluster::ClusterConnection>>();
+  }
+
+  #[cfg(feature = "r2d2")]
+  #[test]
+  fn test_pooled_connection() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let redis_url =
+      std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let client = redis::Client::open(redis_url).unwrap();
+    let pool = r2d2::Pool::builder().max_size(2).build(client).unwrap();
+
+    let mut producer = Producer::init(pool.get().unwrap(), stream, ProducerOpts::default());
+    producer.produce(&[("key", "value")]).unwrap();
+
+    // the connections go back to the pool when the consumers are dropped
+    for _ in 0..3 {
+      let handler = |_id: &str, _message: &Message| Ok(());
+      let opts = ConsumerOpts::default()
+        .start_pos(StartPosition::StartOfStream)
+        .timeout(100);
+      let mut consumer = Consumer::init(pool.get().unwrap(), stream, handler, opts).unwrap();
+      consumer.consume().unwrap();
+      assert_eq!(consumer.handled_messages, 1);
+    }
+    assert_eq!(pool.state().connections, 2);
+
+    delete_stream(stream);
+  }
+}
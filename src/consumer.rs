@@ -1,245 +1,1580 @@
-use anyhow::{Context, Result};
-use redis::streams::{StreamReadOptions, StreamReadReply};
-use redis::{Commands, Connection, RedisResult, Value};
-use std::collections::HashMap;
+use anyhow::{Context as _, Result};
+use redis::streams::{
+  StreamClaimReply, StreamId, StreamPendingCountReply, StreamReadOptions, StreamReadReply,
+};
+use redis::{
+  Commands, Connection, ConnectionLike, ErrorKind, FromRedisValue, Pipeline, RedisResult, Value,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
-pub use super::types::{ConsumerOpts, StartPosition};
+pub use super::types::{
+  Backoff, ConsumerOpts, Endpoint, IntoOutcome, Outcome, Phase, RunSummary, StartPosition,
+};
+use crate::compression::decompress_message;
+use crate::connection::{is_connection_lost, reconnect};
+pub use crate::connection::{AsConnection, Reconnect};
+use crate::encryption::{decrypt_message, DecryptError, Keyring};
+use crate::headers::Headers;
+use crate::iter::Entries;
 
 pub type Message = HashMap<String, Value>;
-// pub type MessageHandler = Fn(&mut Connection, &str, &Message) -> Result<()>;
 
-// A Consumer or Group Consumer handling connection to Redis and able to consume
-// messages.
-pub struct Consumer<'a, F>
+/// Accessors for the field values of a [`Message`], without any lossy
+/// conversion.
+pub trait MessageFields {
+  /// Raw bytes of the field `key`, as written by the producer.
+  fn bytes(&self, key: &str) -> Option<&[u8]>;
+  /// Value of the field `key`, if it is valid UTF-8.
+  fn str(&self, key: &str) -> Option<&str>;
+}
+
+impl MessageFields for Message {
+  fn bytes(&self, key: &str) -> Option<&[u8]> {
+    match self.get(key) {
+      Some(Value::Data(data)) => Some(data),
+      Some(Value::Status(status)) => Some(status.as_bytes()),
+      _ => None,
+    }
+  }
+
+  fn str(&self, key: &str) -> Option<&str> {
+    self
+      .bytes(key)
+      .and_then(|bytes| std::str::from_utf8(bytes).ok())
+  }
+}
+
+/// Handles the messages read by a [`Consumer`].
+///
+/// It is implemented for closures taking the message id and the message
+/// (`FnMut(&str, &Message) -> Result<()>`), for [`OutcomeHandler`] which
+/// returns an [`Outcome`], for [`StreamHandler`] which also receives the name
+/// of the stream the message was read from, and for [`DeliveryHandler`] which
+/// receives a [`Delivery`].
+pub trait Handler {
+  /// Handles the message `id` read from `stream`, and tells what to do with
+  /// it. Returning an error is the same as [`Outcome::Retry`]: the message is
+  /// left unacknowledged and the rest of the batch is still handled.
+  fn handle(&mut self, stream: &str, id: &str, message: &Message) -> Result<Outcome>;
+}
+
+impl<F> Handler for F
 where
   F: FnMut(&str, &Message) -> Result<()>,
 {
+  fn handle(&mut self, _stream: &str, id: &str, message: &Message) -> Result<Outcome> {
+    self(id, message).map(IntoOutcome::into_outcome)
+  }
+}
+
+/// A [`Handler`] built from a closure returning an [`Outcome`]. See
+/// [`with_outcome`].
+pub struct OutcomeHandler<F>(F);
+
+impl<F> Handler for OutcomeHandler<F>
+where
+  F: FnMut(&str, &Message) -> Result<Outcome>,
+{
+  fn handle(&mut self, _stream: &str, id: &str, message: &Message) -> Result<Outcome> {
+    (self.0)(id, message)
+  }
+}
+
+/// Wraps a `FnMut(id, message)` closure returning an [`Outcome`] into a
+/// [`Handler`], to retry, skip or dead-letter messages, or stop consuming.
+///
+/// ```no_run
+/// use redis_stream::consumer::{with_outcome, Consumer, ConsumerOpts, Message, MessageFields, Outcome};
+///
+/// let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+///   .expect("client")
+///   .get_connection()
+///   .expect("connection");
+///
+/// let handler = with_outcome(|_id: &str, message: &Message| {
+///   Ok(match message.str("kind") {
+///     Some("order") => Outcome::Ack,
+///     Some(_) => Outcome::Skip,
+///     None => Outcome::DeadLetter("missing kind".to_string()),
+///   })
+/// });
+/// let opts = ConsumerOpts::default().group("my-group", "worker.1");
+/// let mut consumer = Consumer::init(&mut redis, "my-stream", handler, opts).unwrap();
+/// consumer.consume().expect("consume messages");
+/// ```
+pub fn with_outcome<F>(handler: F) -> OutcomeHandler<F>
+where
+  F: FnMut(&str, &Message) -> Result<Outcome>,
+{
+  OutcomeHandler(handler)
+}
+
+/// A [`Handler`] built from a closure receiving the stream name along with
+/// the message id and the message. See [`with_stream`].
+pub struct StreamHandler<F>(F);
+
+impl<F, R> Handler for StreamHandler<F>
+where
+  F: FnMut(&str, &str, &Message) -> Result<R>,
+  R: IntoOutcome,
+{
+  fn handle(&mut self, stream: &str, id: &str, message: &Message) -> Result<Outcome> {
+    (self.0)(stream, id, message).map(IntoOutcome::into_outcome)
+  }
+}
+
+/// Wraps a `FnMut(stream, id, message)` closure into a [`Handler`], for
+/// consumers reading from several streams.
+pub fn with_stream<F, R>(handler: F) -> StreamHandler<F>
+where
+  F: FnMut(&str, &str, &Message) -> Result<R>,
+  R: IntoOutcome,
+{
+  StreamHandler(handler)
+}
+
+/// A message handed to the handler with its [`Headers`] separated from its
+/// body. See [`with_delivery`].
+pub struct Delivery<'a> {
+  body: Message,
+  headers: Headers,
+  id: &'a str,
+  stream: &'a str,
+}
+
+impl<'a> Delivery<'a> {
+  /// The fields of the message, without the headers.
+  pub fn body(&self) -> &Message {
+    &self.body
+  }
+
+  /// The headers of the message.
+  pub fn headers(&self) -> &Headers {
+    &self.headers
+  }
+
+  /// The id of the message.
+  pub fn id(&self) -> &str {
+    self.id
+  }
+
+  /// The name of the stream the message was read from.
+  pub fn stream(&self) -> &str {
+    self.stream
+  }
+}
+
+/// A [`Handler`] built from a closure receiving a [`Delivery`]. See
+/// [`with_delivery`].
+pub struct DeliveryHandler<F>(F);
+
+impl<F, R> Handler for DeliveryHandler<F>
+where
+  F: FnMut(&Delivery) -> Result<R>,
+  R: IntoOutcome,
+{
+  fn handle(&mut self, stream: &str, id: &str, message: &Message) -> Result<Outcome> {
+    let (headers, body) = match Headers::split(message) {
+      Ok(split) => split,
+      Err(err) => return Ok(Outcome::DeadLetter(err)),
+    };
+    let delivery = Delivery {
+      body,
+      headers,
+      id,
+      stream,
+    };
+    (self.0)(&delivery).map(IntoOutcome::into_outcome)
+  }
+}
+
+/// Wraps a `FnMut(delivery)` closure into a [`Handler`], with the headers of
+/// the messages separated from their body (see
+/// [`headers`](../headers/index.html)). Messages with invalid headers are
+/// moved to the dead-letter stream.
+pub fn with_delivery<F, R>(handler: F) -> DeliveryHandler<F>
+where
+  F: FnMut(&Delivery) -> Result<R>,
+  R: IntoOutcome,
+{
+  DeliveryHandler(handler)
+}
+
+/// Delivery metadata of a message, handed to a [`ContextHandler`].
+pub struct Context<'a> {
+  delivery_count: usize,
+  group: Option<&'a (String, String)>,
+  id: &'a str,
+  opts: &'a ConsumerOpts,
+  phase: Phase,
+  stream: &'a str,
+}
+
+impl<'a> Context<'a> {
+  /// The name of the consumer in the group, if any.
+  pub fn consumer(&self) -> Option<&str> {
+    self.group.map(|(_, consumer_name)| consumer_name.as_str())
+  }
+
+  /// Number of times the message was delivered, this one included (always `1`
+  /// outside of a group).
+  pub fn delivery_count(&self) -> usize {
+    self.delivery_count
+  }
+
+  /// The name of the group, if any.
+  pub fn group(&self) -> Option<&str> {
+    self.group.map(|(group_name, _)| group_name.as_str())
+  }
+
+  /// The id of the message.
+  pub fn id(&self) -> &str {
+    self.id
+  }
+
+  /// The options the consumer was initialized with.
+  pub fn opts(&self) -> &ConsumerOpts {
+    self.opts
+  }
+
+  /// Whether the message is new data, was left pending, or was claimed.
+  pub fn phase(&self) -> Phase {
+    self.phase
+  }
+
+  /// The sequence number part of the id.
+  pub fn sequence(&self) -> u64 {
+    self.id_part(1)
+  }
+
+  /// The name of the stream the message was read from.
+  pub fn stream(&self) -> &str {
+    self.stream
+  }
+
+  /// The millisecond timestamp part of the id.
+  pub fn timestamp(&self) -> u64 {
+    self.id_part(0)
+  }
+
+  fn id_part(&self, index: usize) -> u64 {
+    self
+      .id
+      .split('-')
+      .nth(index)
+      .and_then(|part| part.parse().ok())
+      .unwrap_or(0)
+  }
+}
+
+/// Handles the messages read by a [`Consumer`], along with their [`Context`]
+/// and the connection of the consumer.
+///
+/// It is implemented for every [`Handler`], for closures taking the context
+/// and the message once wrapped with [`with_context`], and for closures taking
+/// the connection once wrapped with [`with_connection`]. `C` is the type of
+/// the connection of the consumer.
+pub trait ContextHandler<C = Connection> {
+  /// Handles `message` delivered with `context`, and tells what to do with it
+  /// (see [`Handler::handle`]). `redis` is the connection of the consumer,
+  /// borrowed for the duration of the call.
+  fn handle_with_context(
+    &mut self,
+    redis: &mut C,
+    context: &Context,
+    message: &Message,
+  ) -> Result<Outcome>;
+
+  /// Takes the commands queued by the last call to `handle_with_context`, to
+  /// run them atomically along with the `XACK` of the message if it is
+  /// acknowledged (see [`with_transaction`]).
+  fn take_transaction(&mut self) -> Option<Pipeline> {
+    None
+  }
+}
+
+impl<H: Handler, C> ContextHandler<C> for H {
+  fn handle_with_context(
+    &mut self,
+    _redis: &mut C,
+    context: &Context,
+    message: &Message,
+  ) -> Result<Outcome> {
+    self.handle(context.stream, context.id, message)
+  }
+}
+
+/// A [`ContextHandler`] built from a closure receiving the [`Context`] and the
+/// message. See [`with_context`].
+pub struct ContextFn<F>(F);
+
+impl<F, R, C> ContextHandler<C> for ContextFn<F>
+where
+  F: FnMut(&Context, &Message) -> Result<R>,
+  R: IntoOutcome,
+{
+  fn handle_with_context(
+    &mut self,
+    _redis: &mut C,
+    context: &Context,
+    message: &Message,
+  ) -> Result<Outcome> {
+    (self.0)(context, message).map(IntoOutcome::into_outcome)
+  }
+}
+
+/// Wraps a `FnMut(context, message)` closure into a [`ContextHandler`].
+///
+/// ```no_run
+/// use redis_stream::consumer::{with_context, Consumer, ConsumerOpts, Context, Message, Phase};
+///
+/// let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+///   .expect("client")
+///   .get_connection()
+///   .expect("connection");
+///
+/// let handler = with_context(|context: &Context, _message: &Message| {
+///   if context.phase() != Phase::New {
+///     println!("{} delivered {} times", context.id(), context.delivery_count());
+///   }
+///   Ok(())
+/// });
+/// let opts = ConsumerOpts::default().group("my-group", "worker.1");
+/// let mut consumer = Consumer::init_multi(&mut redis, &["my-stream"], handler, opts).unwrap();
+/// consumer.consume().expect("consume messages");
+/// ```
+pub fn with_context<F, R>(handler: F) -> ContextFn<F>
+where
+  F: FnMut(&Context, &Message) -> Result<R>,
+  R: IntoOutcome,
+{
+  ContextFn(handler)
+}
+
+/// A [`ContextHandler`] built from a closure receiving the connection of the
+/// consumer along with the message id and the message. See
+/// [`with_connection`].
+pub struct ConnectionFn<F>(F);
+
+impl<F, R, C> ContextHandler<C> for ConnectionFn<F>
+where
+  F: FnMut(&mut C, &str, &Message) -> Result<R>,
+  R: IntoOutcome,
+{
+  fn handle_with_context(
+    &mut self,
+    redis: &mut C,
+    context: &Context,
+    message: &Message,
+  ) -> Result<Outcome> {
+    (self.0)(redis, context.id, message).map(IntoOutcome::into_outcome)
+  }
+}
+
+/// Wraps a `FnMut(redis, id, message)` closure into a [`ContextHandler`], to
+/// write back to Redis with the connection of the consumer instead of opening
+/// another one.
+///
+/// ```no_run
+/// use redis::Commands;
+/// use redis_stream::consumer::{with_connection, Consumer, ConsumerOpts, Message};
+///
+/// let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+///   .expect("client")
+///   .get_connection()
+///   .expect("connection");
+///
+/// let handler = with_connection(|redis: &mut redis::Connection, _id: &str, message: &Message| {
+///   let user: String = redis::from_redis_value(&message["user"])?;
+///   redis.incr::<_, _, ()>(format!("visits:{}", user), 1)?;
+///   Ok(())
+/// });
+/// let opts = ConsumerOpts::default().group("my-group", "worker.1");
+/// let mut consumer = Consumer::init_multi(&mut redis, &["visits"], handler, opts).unwrap();
+/// consumer.consume().expect("consume messages");
+/// ```
+pub fn with_connection<F, R, C>(handler: F) -> ConnectionFn<F>
+where
+  F: FnMut(&mut C, &str, &Message) -> Result<R>,
+  R: IntoOutcome,
+{
+  ConnectionFn(handler)
+}
+
+/// A [`ContextHandler`] built from a closure queuing commands into a
+/// transaction. See [`with_transaction`].
+pub struct TransactionFn<F> {
+  handler: F,
+  transaction: Option<Pipeline>,
+}
+
+impl<F, R, C> ContextHandler<C> for TransactionFn<F>
+where
+  F: FnMut(&mut Pipeline, &str, &Message) -> Result<R>,
+  R: IntoOutcome,
+{
+  fn handle_with_context(
+    &mut self,
+    _redis: &mut C,
+    context: &Context,
+    message: &Message,
+  ) -> Result<Outcome> {
+    let mut transaction = redis::pipe();
+    transaction.atomic();
+    let outcome = (self.handler)(&mut transaction, context.id, message)?;
+    self.transaction = Some(transaction);
+    Ok(outcome.into_outcome())
+  }
+
+  fn take_transaction(&mut self) -> Option<Pipeline> {
+    self.transaction.take()
+  }
+}
+
+/// Wraps a `FnMut(transaction, id, message)` closure into a
+/// [`ContextHandler`], queuing its commands into a `MULTI`/`EXEC`
+/// transaction.
+///
+/// The commands are only run when the message is acknowledged
+/// ([`Outcome::Ack`]), atomically with its `XACK` (group consumers): a
+/// consumer crashing before the transaction leaves the message pending, and
+/// none of its commands applied. They are dropped for any other outcome.
+///
+/// This isn't exactly-once: the transaction runs even if the message was
+/// acknowledged or claimed by another consumer in the meantime (see
+/// [`ConsumerOpts::claim_idle`]), so a message handled by two consumers has
+/// its commands applied twice. Keep `claim_idle` well above the handling
+/// time, or make the commands idempotent. Note that Redis doesn't roll back
+/// a transaction when one of its commands fails, and that transactions require
+/// a connection supporting pipelining (not a `ClusterConnection`).
+///
+/// [`ConsumerOpts::claim_idle`]: ../types/struct.ConsumerOpts.html#method.claim_idle
+///
+/// ```no_run
+/// use redis_stream::consumer::{with_transaction, Consumer, ConsumerOpts, Message};
+///
+/// let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+///   .expect("client")
+///   .get_connection()
+///   .expect("connection");
+///
+/// let handler = with_transaction(|tx: &mut redis::Pipeline, _id: &str, message: &Message| {
+///   let user: String = redis::from_redis_value(&message["user"])?;
+///   tx.incr(format!("visits:{}", user), 1).ignore();
+///   Ok(())
+/// });
+/// let opts = ConsumerOpts::default().group("my-group", "worker.1");
+/// let mut consumer = Consumer::init_multi(&mut redis, &["visits"], handler, opts).unwrap();
+/// consumer.consume().expect("consume messages");
+/// ```
+pub fn with_transaction<F, R>(handler: F) -> TransactionFn<F>
+where
+  F: FnMut(&mut Pipeline, &str, &Message) -> Result<R>,
+  R: IntoOutcome,
+{
+  TransactionFn {
+    handler,
+    transaction: None,
+  }
+}
+
+// A Consumer or Group Consumer handling connection to Redis and able to consume
+// messages from one or several streams.
+pub struct Consumer<H, R = Connection>
+where
+  H: ContextHandler<R::Connection>,
+  R: AsConnection,
+{
+  pub claim_idle: Option<usize>,
   pub count: Option<usize>,
+  pub dead_letter_stream: Option<String>,
+  pub failed_messages: u32,
   pub group: Option<(String, String)>,
   pub handled_messages: u32,
-  pub handler: F,
-  pub next_pos: String,
+  pub handler: H,
+  pub keyring: Option<Keyring>,
+  pub max_deliveries: Option<usize>,
+  /// Position to read from next, for each stream of `streams`.
+  pub next_pos: Vec<String>,
   pub process_pending: bool,
-  pub redis: &'a mut Connection,
-  pub stream: String,
+  /// Endpoint and backoff to reconnect with when the connection is lost.
+  pub reconnect: Option<(Endpoint, Backoff)>,
+  /// The connection, borrowed, owned or pulled from a pool (see
+  /// [`AsConnection`]).
+  pub redis: R,
+  /// Set when the handler returned [`Outcome::Stop`] during the last call to
+  /// `consume` (reset by the next call, and by `run`).
+  pub stopped: bool,
+  pub streams: Vec<String>,
   pub timeout: usize,
+  // Whether the server knows `XAUTOCLAIM` (Redis >= 6.2).
+  autoclaim: bool,
+  // Messages read by an iterator but not yielded yet, with the index of their
+  // stream.
+  pub(crate) buffer: VecDeque<(usize, StreamId)>,
+  // Whether the streams are read by hash slot (Redis Cluster, see
+  // `cluster`).
+  cross_slot: bool,
+  last_claim: Option<Instant>,
+  opts: ConsumerOpts,
 }
 
-impl<'a, F> Consumer<'a, F>
+impl<H, R> Consumer<H, R>
 where
-  F: FnMut(&str, &Message) -> Result<()>,
+  H: ContextHandler<R::Connection>,
+  R: AsConnection,
 {
   /// Initializes a new `stream::Consumer`.
-  pub fn init(
-    redis: &'a mut Connection,
-    stream: &str,
-    handler: F,
+  pub fn init(redis: R, stream: &str, handler: H, opts: ConsumerOpts) -> Result<Self> {
+    Self::init_multi(redis, &[stream], handler, opts)
+  }
+
+  /// Initializes a new `stream::Consumer` reading from several streams at
+  /// once, each one with its own position.
+  ///
+  /// ```no_run
+  /// use redis_stream::consumer::{with_stream, Consumer, ConsumerOpts, Message};
+  ///
+  /// let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+  ///   .expect("client")
+  ///   .get_connection()
+  ///   .expect("connection");
+  ///
+  /// let handler = with_stream(|stream: &str, id: &str, _message: &Message| {
+  ///   println!("{} from {}", id, stream);
+  ///   Ok(())
+  /// });
+  /// let opts = ConsumerOpts::default().group("my-group", "worker.1");
+  /// let mut consumer =
+  ///   Consumer::init_multi(&mut redis, &["logs-a", "logs-b"], handler, opts).unwrap();
+  /// consumer.consume().expect("consume messages");
+  /// ```
+  pub fn init_multi(
+    mut redis: R,
+    streams: &[&str],
+    handler: H,
     opts: ConsumerOpts,
   ) -> Result<Self> {
+    let initial_opts = opts.clone();
+    let claim_idle = opts.claim_idle;
     let count = opts.count;
+    let dead_letter_stream = opts.dead_letter_stream;
+    let keyring = opts.keyring;
+    let max_deliveries = opts.max_deliveries;
     let timeout = opts.timeout;
     let group = opts.group;
     let create_stream_if_not_exists = opts.create_stream_if_not_exists;
     let process_pending = opts.process_pending;
+    let reconnect = opts.reconnect;
     let start_pos = opts.start_pos;
 
     let (group_create_pos, consumer_start_pos) = positions(&group, process_pending, start_pos);
 
     if let Some((group_name, _)) = &group {
-      ensure_stream_and_group(
-        redis,
-        &stream,
-        group_name.as_ref(),
-        &group_create_pos.unwrap(),
-        create_stream_if_not_exists,
-      )?;
+      let group_create_pos = group_create_pos.unwrap();
+      for stream in streams {
+        ensure_stream_and_group(
+          redis.as_connection(),
+          stream,
+          group_name.as_ref(),
+          &group_create_pos,
+          create_stream_if_not_exists,
+        )?;
+      }
     }
 
     Ok(Consumer {
+      claim_idle,
       count,
+      dead_letter_stream,
+      failed_messages: 0,
       group,
       handled_messages: 0,
       handler,
-      next_pos: consumer_start_pos,
+      keyring,
+      max_deliveries,
+      next_pos: vec![consumer_start_pos; streams.len()],
       process_pending,
+      reconnect,
       redis,
-      stream: stream.to_string(),
+      stopped: false,
+      streams: streams.iter().map(|stream| stream.to_string()).collect(),
       timeout,
+      autoclaim: true,
+      buffer: VecDeque::new(),
+      cross_slot: false,
+      last_claim: None,
+      opts: initial_opts,
     })
   }
 
-  /// Handle new messages from the stream, and dispatch them to the registered
-  /// handler.
+  /// Handle new messages from the streams, and dispatch them to the
+  /// registered handler.
+  ///
+  /// With [`ConsumerOpts::reconnect`], a lost connection is reopened (after
+  /// as many attempts as needed) instead of failing, and the messages of the
+  /// interrupted batch are read again.
+  ///
+  /// Fails with a [`DecryptError::UnknownKey`] (see `anyhow::Error::downcast_ref`)
+  /// when a message was encrypted with a key missing from the keyring, which
+  /// may not be deployed yet: the message isn't counted as failed, and it is
+  /// handled first by the next call along with the rest of its batch.
+  ///
+  /// ```no_run
+  /// use redis_stream::consumer::{Consumer, ConsumerOpts, Message};
+  /// use redis_stream::encryption::DecryptError;
+  ///
+  /// # let mut redis = redis::Client::open("redis://127.0.0.1:6379").unwrap().get_connection().unwrap();
+  /// # let handler = |_id: &str, _message: &Message| Ok(());
+  /// # let mut consumer = Consumer::init(&mut redis, "my-stream", handler, ConsumerOpts::default()).unwrap();
+  /// if let Err(err) = consumer.consume() {
+  ///   match err.downcast_ref::<DecryptError>() {
+  ///     Some(DecryptError::UnknownKey(key_id)) => eprintln!("waiting for key {}", key_id),
+  ///     _ => panic!("failed to consume: {:#}", err),
+  ///   }
+  /// }
+  /// ```
+  ///
+  /// [`ConsumerOpts::reconnect`]: ../types/struct.ConsumerOpts.html#method.reconnect
+  /// [`DecryptError::UnknownKey`]: ../encryption/enum.DecryptError.html#variant.UnknownKey
   pub fn consume(&mut self) -> Result<()> {
-    // Prepare options for XREAD
-    let opts = if let Some((group_name, consumer_name)) = &self.group {
-      // We have a consumer group
-      // XREADGROUP GROUP <group_name> <consumer_name> BLOCK <timeout> STREAMS <stream> <start_pos>
-      StreamReadOptions::default()
-        .group(group_name, consumer_name)
-        .block(self.timeout)
-    } else {
-      // We have a simple consumer
-      // XREAD BLOCK <timeout> STREAMS <stream> <start_pos>
-      StreamReadOptions::default().block(self.timeout)
-    };
+    self.stopped = false;
+    match self.consume_batch() {
+      Err(err) if self.reconnect.is_some() && is_connection_error(&err) => self.reconnect(),
+      result => result,
+    }
+  }
 
-    let stream_results: StreamReadReply =
-      self
-        .redis
-        .xread_options(&[&self.stream], &[&self.next_pos], opts)?;
-
-    if !stream_results.keys.is_empty() {
-      let stream = &stream_results.keys[0];
-
-      if self.group.is_some() && self.process_pending && stream.ids.is_empty() {
-        // We ran out of pending results, let's switch to processing most
-        // recent.
-        self.process_pending = false;
-        self.next_pos = String::from(">");
-        return self.consume();
-      } else {
-        // Process the results and set the next position to consume from
-        for message in &stream.ids {
-          // Keep next_post if we are in a consumer-group and it's already `>`
-          if self.next_pos != ">" {
-            // or take the last id
-            self.next_pos = message.id.to_string();
-          }
-          let items = &message.map;
+  fn consume_batch(&mut self) -> Result<()> {
+    if let Some(min_idle) = self.claim_idle {
+      let claim_due = match self.last_claim {
+        Some(last_claim) => last_claim.elapsed().as_millis() >= min_idle as u128,
+        None => true,
+      };
+      if self.group.is_some() && claim_due {
+        self.last_claim = Some(Instant::now());
+        self.claim_idle_messages(min_idle)?;
+        if self.stopped {
+          return Ok(());
+        }
+      }
+    }
 
-          self.process_message(&message.id, items)?;
+    // The messages left buffered by an iterator were already read (and
+    // delivered, in a group), so they are handled before reading new ones
+    if !self.buffer.is_empty() {
+      let mut batches: Vec<(usize, Vec<StreamId>)> = vec![];
+      for (index, message) in self.buffer.drain(..) {
+        match batches.last_mut() {
+          Some((last, ids)) if *last == index => ids.push(message),
+          _ => batches.push((index, vec![message])),
+        }
+      }
+      let mut batches = batches.into_iter();
+      while let Some((index, ids)) = batches.next() {
+        if self.process_messages(index, &ids)?.is_none() {
+          // The next batches are handled by the next call too
+          for (index, ids) in batches {
+            self
+              .buffer
+              .extend(ids.into_iter().map(|message| (index, message)));
+          }
+          break;
         }
       }
+      return Ok(());
     }
 
-    Ok(())
-  }
+    let stream_results = self.read()?;
 
-  /// Process a message by calling the handler and acknowledging the message-id
-  /// to Redis if necessary.
-  fn process_message(&mut self, id: &str, message: &Message) -> Result<()> {
-    // Call handler
-    (self.handler)(id, message)?;
-    self.handled_messages += 1;
-    // XACK if needed
-    if let Some((group_name, _)) = &self.group {
-      let _ack_count: i32 = self.redis.xack(&self.stream, group_name, &[id]).unwrap();
-    }
-    Ok(())
-  }
-}
+    let mut switched_to_new = false;
+    let mut processed = 0;
 
-// Helpers
+    let mut stopped = false;
+    for stream in stream_results.keys {
+      let index = match self.streams.iter().position(|name| name == &stream.key) {
+        Some(index) => index,
+        None => continue,
+      };
 
-/// Create Stream and Consumer-Group if required.
-fn ensure_stream_and_group(
-  redis: &mut Connection,
-  stream: &str,
-  group_name: &str,
-  create_pos: &str,
-  create_stream_if_not_exists: bool,
-) -> Result<()> {
-  let mut result: RedisResult<String> = if create_stream_if_not_exists {
-    redis.xgroup_create_mkstream(stream, group_name, create_pos)
-  } else {
-    redis.xgroup_create(stream, group_name, create_pos)
-  };
+      if stopped {
+        // The messages read from the next streams are handled by the next
+        // call
+        self
+          .buffer
+          .extend(stream.ids.into_iter().map(|message| (index, message)));
+        continue;
+      }
 
-  // Ignore BUSYGROUP errors, it means the group already exists, which is fine.
-  if let Err(err) = &result {
-    if err.to_string() == "BUSYGROUP: Consumer Group name already exists" {
-      result = Ok("OK".to_string());
+      if self.switch_to_new(index, stream.ids.is_empty()) {
+        switched_to_new = true;
+        continue;
+      }
+
+      match self.process_messages(index, &stream.ids)? {
+        Some(count) => processed += count,
+        None => stopped = true,
+      }
+    }
+    if stopped {
+      return Ok(());
     }
-  }
 
-  result.context(format!(
-    "failed to run redis command:\n\
-     XGROUP CREATE {} {} {}{}",
-    stream,
-    group_name,
-    create_pos,
-    if create_stream_if_not_exists {
-      " MKSTREAM"
-    } else {
-      ""
+    if switched_to_new && processed == 0 {
+      return self.consume_batch();
     }
-  ))?;
 
-  Ok(())
-}
+    Ok(())
+  }
 
-/// Returns the tuple (`group_create_position`, `consumer_start_position`)
-/// containing position args for `XGROUP CREATE`, `XREADGROUP` or `XREAD`:
-/// - `group_create_pos`: Position to start in the stream if group is upserted
-///   - for consumer-group:
-///     - `0` for the beginning of the stream
-///     - `$` for the end of the stream (new messages only)
-///     - `<id>` for a specific id
-/// - `start_pos`: Position to start in the stream for consumer
-///   - for groups-consumers:
-///     - `0` for pending messages
-///     - `>` for new messages
-///     - `<id>` for a specific id
-///   - for stream-consumers:
-///     - `0` for the beginning of the stream
-///     - `$` for the end of the stream
-///     - `<id>` for a specific id
-fn positions(
-  group_name: &Option<(String, String)>,
-  process_pending: bool,
-  start_pos: StartPosition,
-) -> (Option<String>, String) {
-  use StartPosition::*;
-  let (group_create_position, consumer_start_position) =
-    match (group_name, process_pending, start_pos) {
-      // no group name: we'll simply XREAD starting from beginning or end
-      (None, _, StartOfStream) => (None, String::from("0")),
-      (None, _, EndOfStream) => (None, String::from("$")),
-      (None, _, Other(id)) => (None, id),
-      // group name and process pending:
-      (_, true, StartOfStream) => str_to_positions("0", "0"),
-      (_, true, EndOfStream) => str_to_positions("$", "0"),
-      (_, true, Other(id)) => (Some(id), String::from("0")),
-      // group name and don't process pending
-      (_, false, StartOfStream) => str_to_positions("0", ">"),
-      (_, false, EndOfStream) => str_to_positions("$", ">"),
-      (_, false, Other(id)) => (Some(id.clone()), id),
+  /// Processes the messages read from the stream at `index`, setting the next
+  /// position to consume from, and returns how many were processed, or `None`
+  /// if the handler returned [`Outcome::Stop`].
+  fn process_messages(&mut self, index: usize, messages: &[StreamId]) -> Result<Option<usize>> {
+    let stream = self.streams[index].clone();
+    let (phase, delivery_counts) = if self.group.is_some() && self.next_pos[index] != ">" {
+      (Phase::Pending, self.delivery_counts(&stream, messages)?)
+    } else {
+      (Phase::New, HashMap::new())
     };
 
-  (group_create_position, consumer_start_position)
-}
+    for (position, message) in messages.iter().enumerate() {
+      let items = &message.map;
+      let delivery_count = delivery_counts.get(&message.id).copied().unwrap_or(1);
 
-// mainly converts &str to Strings...
-#[inline]
-fn str_to_positions(a: &str, b: &str) -> (Option<String>, String) {
-  (Some(a.to_string()), b.to_string())
-}
+      let outcome = match self.process_message(&stream, &message.id, items, phase, delivery_count) {
+        // Left to the next call, like after Stop, until the key is known
+        Err(err) if err.downcast_ref::<DecryptError>().is_some() => {
+          self.buffer.extend(
+            messages[position..]
+              .iter()
+              .cloned()
+              .map(|message| (index, message)),
+          );
+          return Err(err);
+        }
+        result => result?,
+      };
+      if outcome == Outcome::Stop {
+        // The message and the rest of the batch are left pending, and handled
+        // first by the next call (in a group, they are not read again with
+        // `>`)
+        self.buffer.extend(
+          messages[position..]
+            .iter()
+            .cloned()
+            .map(|message| (index, message)),
+        );
+        return Ok(None);
+      }
 
-#[cfg(test)]
-mod tests {
-  use super::*;
-  use crate::test_helpers::*;
-  use anyhow::bail;
-  use redis::FromRedisValue;
+      // Keep next_post if we are in a consumer-group and it's already `>`
+      if self.next_pos[index] != ">" {
+        // or take the last id
+        self.next_pos[index] = message.id.to_string();
+      }
+    }
 
-  fn delete_group(stream: &str, group: &str) {
-    redis_connection()
-      .xgroup_destroy::<&str, &str, bool>(stream, group)
-      .unwrap();
+    Ok(Some(messages.len()))
   }
 
-  #[allow(clippy::unnecessary_wraps)]
-  fn print_message(_id: &str, message: &Message) -> Result<()> {
-    for (k, v) in message {
-      println!("{}: {}", k, String::from_redis_value(&v).unwrap());
+  /// Reads the next batch of messages from the streams, blocking for up to
+  /// `timeout` ms.
+  pub(crate) fn read(&mut self) -> Result<StreamReadReply> {
+    // The pending phase pages through the PEL: `next_pos` moves to the last id
+    // of each batch until an empty batch is read.
+    let stream_results = xread(
+      self.redis.as_connection(),
+      &self.streams,
+      &self.next_pos,
+      &self.group,
+      self.count,
+      self.timeout,
+      &mut self.cross_slot,
+    )?;
+    Ok(stream_results)
+  }
+
+  /// Switches the stream at `index` to new messages (`>`) once its pending
+  /// messages are exhausted (an empty batch was read in the pending phase),
+  /// and tells whether it did.
+  pub(crate) fn switch_to_new(&mut self, index: usize, empty_batch: bool) -> bool {
+    if self.group.is_none() || !self.process_pending || self.next_pos[index] == ">" || !empty_batch
+    {
+      return false;
     }
-    Ok(())
+
+    // We ran out of pending results for this stream, let's switch to
+    // processing most recent.
+    self.next_pos[index] = String::from(">");
+    if self.next_pos.iter().all(|pos| pos == ">") {
+      self.process_pending = false;
+    }
+    true
   }
 
-  #[test]
-  fn test_init_options() {
-    // TODO
+  /// Iterates over the messages of the streams, acknowledging each one when
+  /// the next one is pulled (see [`Entries`](../iter/struct.Entries.html)).
+  ///
+  /// **The iteration ends when a read times out without any new message**,
+  /// after `timeout` ms: call `iter()` again in a loop to keep consuming.
+  /// The messages read but not yielded yet are yielded by the next iterator,
+  /// or handled by the next [`consume`](#method.consume).
+  ///
+  /// ```no_run
+  /// use redis_stream::consumer::{Consumer, ConsumerOpts, Message};
+  ///
+  /// let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+  ///   .expect("client")
+  ///   .get_connection()
+  ///   .expect("connection");
+  ///
+  /// let handler = |_id: &str, _message: &Message| Ok(());
+  /// let opts = ConsumerOpts::default().group("my-group", "worker.1");
+  /// let mut consumer = Consumer::init(&mut redis, "my-stream", handler, opts).unwrap();
+  ///
+  /// loop {
+  ///   for entry in consumer.iter() {
+  ///     let entry = entry.expect("read");
+  ///     println!("{}: {:?}", entry.id, entry.message);
+  ///   }
+  /// }
+  /// ```
+  pub fn iter(&mut self) -> Entries<'_, H, R> {
+    Entries::new(self)
+  }
+
+  /// Keep consuming messages until `shutdown` is set, or the handler returns
+  /// [`Outcome::Stop`] (see `stopped`). A stopped consumer can be run again.
+  ///
+  /// The flag is checked between batches: the batch in progress is always
+  /// fully handled (and acknowledged for group consumers) before returning, so
+  /// shutting down may take up to `timeout` ms while waiting for new messages.
+  ///
+  /// ```no_run
+  /// use redis_stream::consumer::{Consumer, ConsumerOpts, Message};
+  /// use std::sync::atomic::AtomicBool;
+  ///
+  /// let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+  ///   .expect("client")
+  ///   .get_connection()
+  ///   .expect("connection");
+  ///
+  /// let handler = |_id: &str, _message: &Message| Ok(());
+  /// let opts = ConsumerOpts::default().group("my-group", "worker.1");
+  /// let mut consumer = Consumer::init(&mut redis, "my-stream", handler, opts).unwrap();
+  ///
+  /// // set it from another thread (or a signal handler) to stop the loop
+  /// let shutdown = AtomicBool::new(false);
+  /// let summary = consumer.run(&shutdown).expect("run");
+  /// println!("handled {} messages", summary.handled_messages);
+  /// ```
+  pub fn run(&mut self, shutdown: &AtomicBool) -> Result<RunSummary> {
+    self.stopped = false;
+    let started_at = Instant::now();
+    let handled_before = self.handled_messages;
+    let failed_before = self.failed_messages;
+    let mut batches = 0;
+
+    while !shutdown.load(Ordering::SeqCst) && !self.stopped {
+      self.consume()?;
+      batches += 1;
+    }
+
+    Ok(RunSummary {
+      batches,
+      elapsed: started_at.elapsed(),
+      failed_messages: self.failed_messages - failed_before,
+      handled_messages: self.handled_messages - handled_before,
+    })
+  }
+
+  /// Reopens the connection to the endpoint of `reconnect`, backing off
+  /// between attempts, and resumes from the pending messages in a group.
+  fn reconnect(&mut self) -> Result<()> {
+    let (endpoint, backoff) = self.reconnect.as_ref().unwrap();
+    reconnect(self.redis.as_connection(), endpoint, backoff)?;
+
+    // Messages read but not handled yet are read again: simple consumers only
+    // move `next_pos` once a message is handled, and group consumers find them
+    // in their pending messages.
+    self.buffer.clear();
+    if let Some((group_name, _)) = &self.group {
+      // The group is gone if Redis restarted without persistence
+      let (group_create_pos, _) = positions(
+        &self.group,
+        self.opts.process_pending,
+        self.opts.start_pos.clone(),
+      );
+      let group_create_pos = group_create_pos.unwrap();
+      for stream in &self.streams {
+        ensure_stream_and_group(
+          self.redis.as_connection(),
+          stream,
+          group_name,
+          &group_create_pos,
+          self.opts.create_stream_if_not_exists,
+        )?;
+      }
+      self.next_pos = vec![String::from("0"); self.streams.len()];
+      self.process_pending = true;
+    }
+
+    Ok(())
+  }
+
+  /// Claim the messages left idle for at least `min_idle` ms by the consumers
+  /// of the group (this one included, like `XAUTOCLAIM`), and process them.
+  fn claim_idle_messages(&mut self, min_idle: usize) -> Result<()> {
+    let (group_name, consumer_name) = self.group.clone().unwrap();
+    let count = self.count.unwrap_or(100);
+
+    for stream in self.streams.clone() {
+      let mut cursor = String::from("0-0");
+      loop {
+        let claimed = if self.autoclaim {
+          match xautoclaim(
+            self.redis.as_connection(),
+            &stream,
+            &group_name,
+            &consumer_name,
+            min_idle,
+            &cursor,
+            count,
+          ) {
+            Ok((next_cursor, claimed)) => {
+              cursor = next_cursor;
+              claimed
+            }
+            Err(err) if is_unknown_command(&err) => {
+              // Redis < 6.2, fallback to XPENDING + XCLAIM from now on
+              self.autoclaim = false;
+              continue;
+            }
+            Err(err) => {
+              return Err(err).context(format!(
+                "failed to run redis command:\n\
+                 XAUTOCLAIM {} {} {} {} {} COUNT {}",
+                stream, group_name, consumer_name, min_idle, cursor, count
+              ))
+            }
+          }
+        } else {
+          let (next_cursor, claimed) = xpending_xclaim(
+            self.redis.as_connection(),
+            &stream,
+            &group_name,
+            &consumer_name,
+            min_idle,
+            &cursor,
+            count,
+          )?;
+          cursor = next_cursor;
+          claimed
+        };
+
+        let delivery_counts = self.delivery_counts(&stream, &claimed)?;
+        for message in &claimed {
+          if message.map.is_empty() {
+            // The entry was deleted from the stream while pending, there is
+            // nothing left to handle.
+            let _ack_count: i32 =
+              self
+                .redis
+                .as_connection()
+                .xack(&stream, &group_name, &[&message.id])?;
+            continue;
+          }
+          let delivery_count = delivery_counts.get(&message.id).copied().unwrap_or(1);
+          let outcome = self.process_message(
+            &stream,
+            &message.id,
+            &message.map,
+            Phase::Claimed,
+            delivery_count,
+          )?;
+          if outcome == Outcome::Stop {
+            return Ok(());
+          }
+        }
+
+        if cursor == "0-0" {
+          break;
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Process a message by calling the handler and acting on the returned
+  /// [`Outcome`]: acknowledging the message-id to Redis if necessary, or moving
+  /// it to the dead-letter stream.
+  fn process_message(
+    &mut self,
+    stream: &str,
+    id: &str,
+    message: &Message,
+    phase: Phase,
+    delivery_count: usize,
+  ) -> Result<Outcome> {
+    // Call handler
+    let context = Context {
+      delivery_count,
+      group: self.group.as_ref(),
+      id,
+      opts: &self.opts,
+      phase,
+      stream,
+    };
+    // Messages that can't be decrypted with a known key, or decompressed, are
+    // dead-lettered, while an unknown key isn't a failure of the handler
+    let result = match open_message(self.keyring.as_ref(), stream, message)? {
+      Ok(opened) => self.handler.handle_with_context(
+        self.redis.as_connection(),
+        &context,
+        opened.as_ref().unwrap_or(message),
+      ),
+      Err(reason) => Ok(Outcome::DeadLetter(reason)),
+    };
+    let transaction = self.handler.take_transaction();
+    let outcome = match result {
+      Ok(outcome) => outcome,
+      Err(err) => {
+        self.failed_messages += 1;
+        self.retry(stream, id, message, &format!("{:#}", err))?;
+        return Ok(Outcome::Retry);
+      }
+    };
+
+    match &outcome {
+      Outcome::Ack => {
+        match transaction {
+          Some(transaction) => self.commit(stream, id, transaction)?,
+          None => self.ack(stream, id)?,
+        }
+        self.handled_messages += 1;
+      }
+      Outcome::DeadLetter(reason) => self.dead_letter(stream, id, message, reason)?,
+      Outcome::Retry => self.retry(stream, id, message, "retried by the handler")?,
+      Outcome::Skip => self.ack(stream, id)?,
+      Outcome::Stop => self.stopped = true,
+    }
+
+    Ok(outcome)
+  }
+
+  /// Fetches the delivery counts of `messages`, just read from the PEL or
+  /// claimed by this consumer, in a single `XPENDING`.
+  fn delivery_counts(
+    &mut self,
+    stream: &str,
+    messages: &[StreamId],
+  ) -> Result<HashMap<String, usize>> {
+    let (group_name, consumer_name) = match (&self.group, messages.first(), messages.last()) {
+      (Some(group), Some(_), Some(_)) => group,
+      _ => return Ok(HashMap::new()),
+    };
+    let (first, last) = (&messages[0].id, &messages[messages.len() - 1].id);
+
+    let pending: StreamPendingCountReply = self
+      .redis
+      .as_connection()
+      .xpending_consumer_count(
+        stream,
+        group_name,
+        first,
+        last,
+        messages.len(),
+        consumer_name,
+      )
+      .context(format!(
+        "failed to run redis command:\n\
+         XPENDING {} {} {} {} {} {}",
+        stream,
+        group_name,
+        first,
+        last,
+        messages.len(),
+        consumer_name
+      ))?;
+    Ok(
+      pending
+        .ids
+        .into_iter()
+        .map(|pending| (pending.id, pending.times_delivered))
+        .collect(),
+    )
+  }
+
+  /// XACK the message if we are in a consumer-group.
+  pub(crate) fn ack(&mut self, stream: &str, id: &str) -> Result<()> {
+    if let Some((group_name, _)) = &self.group {
+      let _ack_count: i32 = self
+        .redis
+        .as_connection()
+        .xack(stream, group_name, &[id])
+        .context(format!(
+          "failed to run redis command:\n\
+         XACK {} {} {}",
+          stream, group_name, id
+        ))?;
+    }
+    Ok(())
+  }
+
+  /// Runs the commands queued by the handler and the XACK of the message (if
+  /// we are in a consumer-group) in a single `MULTI`/`EXEC`.
+  fn commit(&mut self, stream: &str, id: &str, mut transaction: Pipeline) -> Result<()> {
+    if let Some((group_name, _)) = &self.group {
+      transaction.xack(stream, group_name, &[id]).ignore();
+    }
+    transaction
+      .query::<()>(self.redis.as_connection())
+      .context(format!(
+        "failed to run the transaction of message {} from {}",
+        id, stream
+      ))?;
+    Ok(())
+  }
+
+  /// Leaves a message pending, or moves it to the dead-letter stream if it
+  /// reached `max_deliveries`.
+  fn retry(&mut self, stream: &str, id: &str, message: &Message, error: &str) -> Result<()> {
+    let (group_name, max_deliveries) = match (&self.group, self.max_deliveries) {
+      (Some((group_name, _)), Some(max_deliveries)) => (group_name, max_deliveries),
+      _ => return Ok(()),
+    };
+
+    let pending: StreamPendingCountReply = self
+      .redis
+      .as_connection()
+      .xpending_count(stream, group_name, id, id, 1)
+      .context(format!(
+        "failed to run redis command:\n\
+         XPENDING {} {} {} {} 1",
+        stream, group_name, id, id
+      ))?;
+    let deliveries = pending
+      .ids
+      .first()
+      .map_or(0, |pending| pending.times_delivered);
+    if deliveries < max_deliveries {
+      return Ok(());
+    }
+
+    self.dead_letter(stream, id, message, error)
+  }
+
+  /// Moves a message to the dead-letter stream (and acknowledges it if we are
+  /// in a consumer-group).
+  pub(crate) fn dead_letter(
+    &mut self,
+    stream: &str,
+    id: &str,
+    message: &Message,
+    error: &str,
+  ) -> Result<()> {
+    let dead_letter_stream = match &self.dead_letter_stream {
+      Some(dead_letter_stream) => dead_letter_stream.clone(),
+      None => format!("{}:dead-letter", stream),
+    };
+    let group_name = self
+      .group
+      .as_ref()
+      .map(|(group_name, _)| group_name.as_str());
+    dead_letter(
+      self.redis.as_connection(),
+      &dead_letter_stream,
+      stream,
+      group_name,
+      id,
+      message,
+      error,
+    )
+  }
+}
+
+/// Returns a flag set on `SIGTERM` or `SIGINT`, to be given to
+/// [`Consumer::run`](struct.Consumer.html#method.run).
+///
+/// Requires the `signal` feature.
+#[cfg(feature = "signal")]
+pub fn shutdown_on_signals() -> Result<std::sync::Arc<AtomicBool>> {
+  use signal_hook::consts::{SIGINT, SIGTERM};
+
+  let shutdown = std::sync::Arc::new(AtomicBool::new(false));
+  for signal in &[SIGTERM, SIGINT] {
+    signal_hook::flag::register(*signal, shutdown.clone())
+      .context(format!("failed to register handler for signal {}", signal))?;
+  }
+  Ok(shutdown)
+}
+
+// Helpers
+
+/// Whether `err` comes from a lost connection to Redis, see
+/// [`is_connection_lost`].
+fn is_connection_error(err: &anyhow::Error) -> bool {
+  err
+    .chain()
+    .any(|err| match err.downcast_ref::<redis::RedisError>() {
+      Some(err) => is_connection_lost(err),
+      None => false,
+    })
+}
+
+/// Decrypts and decompresses the message if needed, returning `None` when it
+/// is neither encrypted nor compressed. Fails with a [`DecryptError`] when it
+/// was encrypted with an unknown key (which may not be deployed yet), and
+/// returns the reason to dead-letter it when it can't be decrypted or
+/// decompressed.
+pub(crate) fn open_message(
+  keyring: Option<&Keyring>,
+  stream: &str,
+  message: &Message,
+) -> Result<std::result::Result<Option<Message>, String>> {
+  let decrypted = match decrypt_message(keyring, stream, message) {
+    Ok(decrypted) => decrypted,
+    Err(err @ DecryptError::UnknownKey(_)) => return Err(err.into()),
+    Err(err) => return Ok(Err(err.to_string())),
+  };
+
+  let decompressed = match decompress_message(decrypted.as_ref().unwrap_or(message)) {
+    Ok(decompressed) => decompressed,
+    Err(err) => return Ok(Err(format!("{:#}", err))),
+  };
+  Ok(Ok(decompressed.or(decrypted)))
+}
+
+/// Adds a copy of the message `id` to `dead_letter_stream`, along with where
+/// it comes from and the `error` it failed with, and acknowledges it in the
+/// source group (if any), in a single transaction. Connections without
+/// pipelining (Redis Cluster) run both commands one after the other instead:
+/// the copy may then be added twice if the consumer crashes in between.
+pub(crate) fn dead_letter<C: ConnectionLike>(
+  redis: &mut C,
+  dead_letter_stream: &str,
+  stream: &str,
+  group_name: Option<&str>,
+  id: &str,
+  message: &Message,
+  error: &str,
+) -> Result<()> {
+  let pipe = dead_letter_pipeline(dead_letter_stream, stream, group_name, id, message, error);
+  let result = if redis.supports_pipelining() {
+    pipe.query::<()>(redis)
+  } else {
+    pipe.cmd_iter().try_for_each(|cmd| cmd.query::<()>(redis))
+  };
+  result.context(format!(
+    "failed to move message {} from {} to dead-letter stream {}",
+    id, stream, dead_letter_stream
+  ))?;
+
+  Ok(())
+}
+
+/// The `XADD` to the dead-letter stream and the `XACK` of [`dead_letter`], in
+/// a transaction.
+pub(crate) fn dead_letter_pipeline(
+  dead_letter_stream: &str,
+  stream: &str,
+  group_name: Option<&str>,
+  id: &str,
+  message: &Message,
+  error: &str,
+) -> Pipeline {
+  let mut xadd = redis::cmd("XADD");
+  xadd.arg(dead_letter_stream).arg("*");
+  for (key, value) in message {
+    if let Value::Data(value) = value {
+      xadd.arg(key).arg(&value[..]);
+    }
+  }
+  xadd.arg("_dlq_id").arg(id).arg("_dlq_stream").arg(stream);
+  if let Some(group_name) = group_name {
+    xadd.arg("_dlq_group").arg(group_name);
+  }
+  xadd.arg("_dlq_error").arg(error);
+
+  let mut pipe = redis::pipe();
+  pipe.atomic().add_command(xadd).ignore();
+  if let Some(group_name) = group_name {
+    pipe.xack(stream, group_name, &[id]).ignore();
+  }
+  pipe
+}
+
+/// Reads `streams` from `positions` with `XREAD` (or `XREADGROUP` in a
+/// `group`), blocking for up to `timeout` ms.
+///
+/// Once Redis Cluster refused to read streams living in different hash slots
+/// (`CROSSSLOT` error), `cross_slot` is set and the streams are read with one
+/// command per slot instead (see [`cluster`](../cluster/index.html)).
+pub(crate) fn xread<C: ConnectionLike>(
+  redis: &mut C,
+  streams: &[String],
+  positions: &[String],
+  group: &Option<(String, String)>,
+  count: Option<usize>,
+  timeout: usize,
+  cross_slot: &mut bool,
+) -> RedisResult<StreamReadReply> {
+  // Prepare options for XREAD
+  let opts = |block: Option<usize>| {
+    let mut opts = if let Some((group_name, consumer_name)) = group {
+      // We have a consumer group
+      // XREADGROUP GROUP <group_name> <consumer_name> BLOCK <timeout> [COUNT <count>] STREAMS <streams...> <positions...>
+      StreamReadOptions::default().group(group_name, consumer_name)
+    } else {
+      // We have a simple consumer
+      // XREAD BLOCK <timeout> [COUNT <count>] STREAMS <streams...> <positions...>
+      StreamReadOptions::default()
+    };
+    if let Some(block) = block {
+      opts = opts.block(block);
+    }
+    if let Some(count) = count {
+      opts = opts.count(count);
+    }
+    opts
+  };
+
+  if !*cross_slot {
+    match redis.xread_options(streams, positions, opts(Some(timeout))) {
+      Err(err) if err.kind() == ErrorKind::CrossSlot => *cross_slot = true,
+      result => return result,
+    }
+  }
+
+  // One read per slot: the blocking time is shared between the slots until
+  // one of them has messages, the others are then read without blocking.
+  let slots = crate::cluster::slot_groups(streams);
+  let block = std::cmp::max(timeout / slots.len(), 1);
+  let mut reply = StreamReadReply::default();
+  for indexes in slots {
+    let slot_streams: Vec<&str> = indexes.iter().map(|i| streams[*i].as_str()).collect();
+    let slot_positions: Vec<&str> = indexes.iter().map(|i| positions[*i].as_str()).collect();
+    let has_messages = reply.keys.iter().any(|key| !key.ids.is_empty());
+    let slot_reply: StreamReadReply = redis.xread_options(
+      &slot_streams,
+      &slot_positions,
+      opts(if has_messages { None } else { Some(block) }),
+    )?;
+    reply.keys.extend(slot_reply.keys);
+  }
+  Ok(reply)
+}
+
+/// Runs `XAUTOCLAIM <stream> <group> <consumer> <min_idle> <cursor> COUNT
+/// <count>` and returns the next cursor along with the claimed messages.
+fn xautoclaim<C: ConnectionLike>(
+  redis: &mut C,
+  stream: &str,
+  group_name: &str,
+  consumer_name: &str,
+  min_idle: usize,
+  cursor: &str,
+  count: usize,
+) -> RedisResult<(String, Vec<StreamId>)> {
+  let reply: Value = redis::cmd("XAUTOCLAIM")
+    .arg(stream)
+    .arg(group_name)
+    .arg(consumer_name)
+    .arg(min_idle)
+    .arg(cursor)
+    .arg("COUNT")
+    .arg(count)
+    .query(redis)?;
+
+  // Redis 6.2 replies with [cursor, entries], Redis 7 adds the deleted ids.
+  match reply {
+    Value::Bulk(ref items) if items.len() >= 2 => {
+      let cursor = String::from_redis_value(&items[0])?;
+      let claimed = StreamClaimReply::from_redis_value(&items[1])?;
+      Ok((cursor, claimed.ids))
+    }
+    _ => Err((ErrorKind::TypeError, "unexpected XAUTOCLAIM reply").into()),
+  }
+}
+
+/// Same as `xautoclaim`, for Redis versions without `XAUTOCLAIM`: claims the
+/// messages left idle for at least `min_idle` ms (by any consumer) among the
+/// `count` first pending messages from `cursor`.
+fn xpending_xclaim<C: ConnectionLike>(
+  redis: &mut C,
+  stream: &str,
+  group_name: &str,
+  consumer_name: &str,
+  min_idle: usize,
+  cursor: &str,
+  count: usize,
+) -> Result<(String, Vec<StreamId>)> {
+  let start = if cursor == "0-0" { "-" } else { cursor };
+  let pending: StreamPendingCountReply = redis
+    .xpending_count(stream, group_name, start, "+", count)
+    .context(format!(
+      "failed to run redis command:\n\
+       XPENDING {} {} {} + {}",
+      stream, group_name, start, count
+    ))?;
+
+  // Next page starts right after the last pending id (no exclusive ranges
+  // before Redis 6.2).
+  let next_cursor = match pending.ids.last() {
+    Some(last) if pending.ids.len() >= count => next_id(&last.id),
+    _ => String::from("0-0"),
+  };
+
+  let ids: Vec<String> = pending
+    .ids
+    .into_iter()
+    .filter(|pending| pending.last_delivered_ms >= min_idle)
+    .map(|pending| pending.id)
+    .collect();
+  if ids.is_empty() {
+    return Ok((next_cursor, vec![]));
+  }
+
+  let claimed: StreamClaimReply = redis
+    .xclaim(stream, group_name, consumer_name, min_idle, &ids)
+    .context(format!(
+      "failed to run redis command:\n\
+       XCLAIM {} {} {} {} {}",
+      stream,
+      group_name,
+      consumer_name,
+      min_idle,
+      ids.join(" ")
+    ))?;
+  Ok((next_cursor, claimed.ids))
+}
+
+/// Returns the smallest stream id greater than `id`, or `0-0` (the end of the
+/// scan) when there is none.
+fn next_id(id: &str) -> String {
+  match id.split_once('-') {
+    Some((ms, seq)) => match (ms.parse::<u64>(), seq.parse::<u64>()) {
+      (Ok(ms), Ok(seq)) => match (seq.checked_add(1), ms.checked_add(1)) {
+        (Some(seq), _) => format!("{}-{}", ms, seq),
+        (None, Some(ms)) => format!("{}-0", ms),
+        (None, None) => String::from("0-0"),
+      },
+      _ => id.to_string(),
+    },
+    None => format!("{}-1", id),
+  }
+}
+
+fn is_unknown_command(err: &redis::RedisError) -> bool {
+  err.kind() == ErrorKind::ResponseError && err.to_string().contains("unknown command")
+}
+
+/// Create Stream and Consumer-Group if required.
+pub(crate) fn ensure_stream_and_group<C: ConnectionLike>(
+  redis: &mut C,
+  stream: &str,
+  group_name: &str,
+  create_pos: &str,
+  create_stream_if_not_exists: bool,
+) -> Result<()> {
+  let mut result: RedisResult<String> = if create_stream_if_not_exists {
+    redis.xgroup_create_mkstream(stream, group_name, create_pos)
+  } else {
+    redis.xgroup_create(stream, group_name, create_pos)
+  };
+
+  // Ignore BUSYGROUP errors, it means the group already exists, which is fine.
+  if let Err(err) = &result {
+    if err.to_string() == "BUSYGROUP: Consumer Group name already exists" {
+      result = Ok("OK".to_string());
+    }
+  }
+
+  result.context(format!(
+    "failed to run redis command:\n\
+     XGROUP CREATE {} {} {}{}",
+    stream,
+    group_name,
+    create_pos,
+    if create_stream_if_not_exists {
+      " MKSTREAM"
+    } else {
+      ""
+    }
+  ))?;
+
+  Ok(())
+}
+
+/// Returns the tuple (`group_create_position`, `consumer_start_position`)
+/// containing position args for `XGROUP CREATE`, `XREADGROUP` or `XREAD`:
+/// - `group_create_pos`: Position to start in the stream if group is upserted
+///   - for consumer-group:
+///     - `0` for the beginning of the stream
+///     - `$` for the end of the stream (new messages only)
+///     - `<id>` for a specific id
+/// - `start_pos`: Position to start in the stream for consumer
+///   - for groups-consumers:
+///     - `0` for pending messages
+///     - `>` for new messages
+///     - `<id>` for a specific id
+///   - for stream-consumers:
+///     - `0` for the beginning of the stream
+///     - `$` for the end of the stream
+///     - `<id>` for a specific id
+pub(crate) fn positions(
+  group_name: &Option<(String, String)>,
+  process_pending: bool,
+  start_pos: StartPosition,
+) -> (Option<String>, String) {
+  use StartPosition::*;
+  let (group_create_position, consumer_start_position) =
+    match (group_name, process_pending, start_pos) {
+      // no group name: we'll simply XREAD starting from beginning or end
+      (None, _, StartOfStream) => (None, String::from("0")),
+      (None, _, EndOfStream) => (None, String::from("$")),
+      (None, _, Other(id)) => (None, id),
+      // group name and process pending:
+      (_, true, StartOfStream) => str_to_positions("0", "0"),
+      (_, true, EndOfStream) => str_to_positions("$", "0"),
+      (_, true, Other(id)) => (Some(id), String::from("0")),
+      // group name and don't process pending
+      (_, false, StartOfStream) => str_to_positions("0", ">"),
+      (_, false, EndOfStream) => str_to_positions("$", ">"),
+      (_, false, Other(id)) => (Some(id.clone()), id),
+    };
+
+  (group_create_position, consumer_start_position)
+}
+
+// mainly converts &str to Strings...
+#[inline]
+fn str_to_positions(a: &str, b: &str) -> (Option<String>, String) {
+  (Some(a.to_string()), b.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_helpers::*;
+  use anyhow::bail;
+  use redis::FromRedisValue;
+
+  #[allow(clippy::unnecessary_wraps)]
+  fn print_message(_id: &str, message: &Message) -> Result<()> {
+    for (k, v) in message {
+      println!("{}: {}", k, String::from_redis_value(v).unwrap());
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn test_init_options() {
+    // TODO
     // - Test with default values
     // - Test with custom values
     // - Test guards (group_name or consumer_name)
@@ -259,7 +1594,7 @@ mod tests {
     let opts = ConsumerOpts::default()
       .create_stream_if_not_exists(true)
       .group(group_name, consumer_name);
-    Consumer::init(&mut redis_c, &stream, print_message, opts).unwrap();
+    Consumer::init(&mut redis_c, stream, print_message, opts).unwrap();
     assert!(key_exists(&mut redis, stream));
     // with length = 0
     let len: usize = redis.xlen(stream).unwrap();
@@ -515,6 +1850,807 @@ mod tests {
     delete_stream(stream);
   }
 
+  #[test]
+  fn test_consume_count() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    for i in 0..5 {
+      crate::produce(&mut redis, stream, &[("key", &format!("value_{}", i))]).unwrap();
+    }
+
+    // simple consumer: it reads at most `count` messages per batch
+    {
+      let handler = |_id: &str, _message: &Message| Ok(());
+      let opts = ConsumerOpts::default()
+        .start_pos(StartPosition::StartOfStream)
+        .count(2)
+        .timeout(10);
+      let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+
+      for handled in &[2, 4, 5, 5] {
+        consumer.consume().unwrap();
+        assert_eq!(consumer.handled_messages, *handled);
+      }
+    }
+
+    // consumer group: it leaves the whole backlog pending
+    {
+      let handler = |_id: &str, _message: &Message| bail!("I don't ack message");
+      let opts = ConsumerOpts::default()
+        .group(group_name, consumer_name)
+        .start_pos(StartPosition::StartOfStream)
+        .timeout(10);
+      let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+      consumer.consume().unwrap();
+      assert_eq!(consumer.failed_messages, 5);
+    }
+
+    // consumer group: it drains the pending messages `count` at a time
+    {
+      let handler = |_id: &str, _message: &Message| Ok(());
+      let opts = ConsumerOpts::default()
+        .group(group_name, consumer_name)
+        .process_pending(true)
+        .count(2)
+        .timeout(10);
+      let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+
+      for (handled, pending_count) in &[(2, 3), (4, 1), (5, 0)] {
+        consumer.consume().unwrap();
+        assert_eq!(consumer.handled_messages, *handled);
+        let pending: redis::streams::StreamPendingReply =
+          redis.xpending(stream, group_name).unwrap();
+        assert_eq!(pending.count(), *pending_count);
+        assert!(consumer.process_pending);
+      }
+
+      // and switches to new messages once the pending ones are drained
+      consumer.consume().unwrap();
+      assert_eq!(consumer.handled_messages, 5);
+      assert!(!consumer.process_pending);
+      assert_eq!(consumer.next_pos, vec![">".to_string()]);
+    }
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+  }
+
+  #[test]
+  fn test_consume_multi() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream_a = &format!("test-stream-{}", random_string(25));
+    let stream_b = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    crate::produce(&mut redis, stream_a, &[("key", "value_a")]).unwrap();
+    crate::produce(&mut redis, stream_b, &[("key", "value_b")]).unwrap();
+
+    // simple consumer: it tells the handler where each message comes from
+    {
+      let mut messages = vec![];
+      let handler = with_stream(|stream: &str, _id: &str, message: &Message| {
+        let value = String::from_redis_value(message.get("key").unwrap())?;
+        messages.push((stream.to_string(), value));
+        Ok(())
+      });
+      let opts = ConsumerOpts::default().start_pos(StartPosition::StartOfStream);
+      let mut consumer =
+        Consumer::init_multi(&mut redis_c, &[stream_a, stream_b], handler, opts).unwrap();
+      consumer.consume().unwrap();
+      assert!(consumer.next_pos.iter().all(|pos| pos != "0"));
+      drop(consumer);
+
+      let mut expected = vec![
+        (stream_a.to_string(), "value_a".to_string()),
+        (stream_b.to_string(), "value_b".to_string()),
+      ];
+      messages.sort();
+      expected.sort();
+      assert_eq!(messages, expected);
+    }
+
+    // consumer group: it reads and acks all streams, pending first
+    {
+      let mut messages = vec![];
+      let handler = with_stream(|stream: &str, _id: &str, _message: &Message| {
+        messages.push(stream.to_string());
+        Ok(())
+      });
+      let opts = ConsumerOpts::default()
+        .group(group_name, consumer_name)
+        .start_pos(StartPosition::StartOfStream)
+        .process_pending(true);
+      let mut consumer =
+        Consumer::init_multi(&mut redis_c, &[stream_a, stream_b], handler, opts).unwrap();
+      consumer.consume().unwrap();
+      assert_eq!(consumer.next_pos, vec![">".to_string(), ">".to_string()]);
+      assert!(!consumer.process_pending);
+      drop(consumer);
+
+      let mut expected = vec![stream_a.to_string(), stream_b.to_string()];
+      messages.sort();
+      expected.sort();
+      assert_eq!(messages, expected);
+
+      for stream in &[stream_a, stream_b] {
+        let pending: redis::streams::StreamPendingReply =
+          redis.xpending(*stream, group_name).unwrap();
+        assert_eq!(pending.count(), 0);
+      }
+    }
+
+    for stream in &[stream_a, stream_b] {
+      delete_group(stream, group_name);
+      delete_stream(stream);
+    }
+  }
+
+  #[test]
+  fn test_read_by_slot() {
+    let tag = random_string(25);
+    let stream_a = &format!("test-stream-{{{}}}-a", tag);
+    let stream_b = &format!("test-stream-{}-b", random_string(25));
+    let stream_c = &format!("test-stream-{{{}}}-c", tag);
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    for stream in &[stream_a, stream_b, stream_c] {
+      crate::produce(&mut redis, stream, &[("key", "value")]).unwrap();
+    }
+
+    let mut messages = vec![];
+    let handler = with_stream(|stream: &str, _id: &str, _message: &Message| {
+      messages.push(stream.to_string());
+      Ok(())
+    });
+    let opts = ConsumerOpts::default()
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(300);
+    let mut consumer =
+      Consumer::init_multi(&mut redis_c, &[stream_a, stream_b, stream_c], handler, opts).unwrap();
+    // as after a `CROSSSLOT` error from Redis Cluster
+    consumer.cross_slot = true;
+
+    // it reads the streams of every slot
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 3);
+
+    // it shares the blocking time between the slots
+    let started_at = Instant::now();
+    consumer.consume().unwrap();
+    let elapsed = started_at.elapsed().as_millis();
+    assert!((250..600).contains(&elapsed), "blocked for {} ms", elapsed);
+    drop(consumer);
+
+    let mut expected = vec![
+      stream_a.to_string(),
+      stream_b.to_string(),
+      stream_c.to_string(),
+    ];
+    messages.sort();
+    expected.sort();
+    assert_eq!(messages, expected);
+
+    for stream in &[stream_a, stream_b, stream_c] {
+      delete_stream(stream);
+    }
+  }
+
+  #[test]
+  fn test_next_id() {
+    assert_eq!(next_id("1526919030474-55"), "1526919030474-56");
+    assert_eq!(next_id("1526919030474"), "1526919030474-1");
+    assert_eq!(
+      next_id("1526919030474-18446744073709551615"),
+      "1526919030475-0"
+    );
+    assert_eq!(next_id("18446744073709551615-18446744073709551615"), "0-0");
+  }
+
+  #[test]
+  fn test_claim_idle() {
+    use std::thread;
+    use std::time::Duration;
+
+    let group_name = &format!("test-group-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    // a consumer reads messages but dies before acknowledging them
+    {
+      let handler = |_id: &str, _message: &Message| bail!("I die before acking");
+      let opts = ConsumerOpts::default()
+        .group(group_name, "dead-consumer")
+        .start_pos(StartPosition::StartOfStream)
+        .process_pending(false);
+      let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+      crate::produce(&mut redis, stream, &[("key", "value_1")]).unwrap();
+      consumer.consume().unwrap_or(());
+      crate::produce(&mut redis, stream, &[("key", "value_2")]).unwrap();
+      consumer.consume().unwrap_or(());
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    // it doesn't claim messages that are not idle for long enough
+    {
+      let mut messages = vec![];
+      let handler = |_id: &str, message: &Message| {
+        messages.push(message.clone());
+        Ok(())
+      };
+      let opts = ConsumerOpts::default()
+        .group(group_name, "live-consumer")
+        .claim_idle(60_000)
+        .timeout(10);
+      let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+      consumer.consume().unwrap();
+      assert!(messages.is_empty());
+    }
+
+    // it claims idle messages using XPENDING + XCLAIM
+    {
+      let (cursor, claimed) = xpending_xclaim(
+        &mut redis,
+        stream,
+        group_name,
+        "other-consumer",
+        50,
+        "0-0",
+        1,
+      )
+      .unwrap();
+      assert_eq!(cursor, next_id(&claimed[0].id));
+      assert_eq!(claimed.len(), 1);
+      let value = String::from_redis_value(claimed[0].map.get("key").unwrap()).unwrap();
+      assert_eq!(value, "value_1".to_string());
+
+      // including the messages of the claiming consumer, like XAUTOCLAIM
+      thread::sleep(Duration::from_millis(100));
+      let (_, claimed) = xpending_xclaim(
+        &mut redis,
+        stream,
+        group_name,
+        "other-consumer",
+        50,
+        "0-0",
+        1,
+      )
+      .unwrap();
+      assert_eq!(claimed.len(), 1);
+      let value = String::from_redis_value(claimed[0].map.get("key").unwrap()).unwrap();
+      assert_eq!(value, "value_1".to_string());
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    // it claims idle messages and handles them
+    {
+      let mut messages = vec![];
+      let handler = |_id: &str, message: &Message| {
+        messages.push(message.clone());
+        Ok(())
+      };
+      let opts = ConsumerOpts::default()
+        .group(group_name, "live-consumer")
+        .claim_idle(50)
+        .count(1)
+        .timeout(10);
+      let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+      consumer.consume().unwrap();
+      assert_eq!(consumer.handled_messages, 2);
+      let value = String::from_redis_value(messages.pop().unwrap().get("key").unwrap()).unwrap();
+      assert_eq!(value, "value_2".to_string());
+      let value = String::from_redis_value(messages.pop().unwrap().get("key").unwrap()).unwrap();
+      assert_eq!(value, "value_1".to_string());
+
+      let pending: redis::streams::StreamPendingReply = redis.xpending(stream, group_name).unwrap();
+      assert_eq!(pending.count(), 0);
+    }
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+  }
+
+  #[test]
+  fn test_claim_idle_stop() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    // a consumer leaves a message pending, and a new one is produced
+    {
+      let handler = |_id: &str, _message: &Message| bail!("I die before acking");
+      let opts = ConsumerOpts::default()
+        .group(group_name, "dead-consumer")
+        .start_pos(StartPosition::StartOfStream)
+        .timeout(10);
+      let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+      crate::produce(&mut redis, stream, &[("key", "value_1")]).unwrap();
+      consumer.consume().unwrap();
+    }
+    crate::produce(&mut redis, stream, &[("key", "value_2")]).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    // it doesn't read new messages once a claimed message returned `Stop`
+    let mut calls = 0;
+    let handler = with_outcome(|_id: &str, _message: &Message| {
+      calls += 1;
+      Ok(Outcome::Stop)
+    });
+    let opts = ConsumerOpts::default()
+      .group(group_name, "live-consumer")
+      .claim_idle(50)
+      .timeout(10);
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+    consumer.consume().unwrap();
+    assert!(consumer.stopped);
+    drop(consumer);
+    assert_eq!(calls, 1);
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+  }
+
+  #[test]
+  fn test_max_deliveries() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let dead_letter_stream = &format!("{}:dead-letter", stream);
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    let opts = || {
+      ConsumerOpts::default()
+        .group(group_name, consumer_name)
+        .start_pos(StartPosition::StartOfStream)
+        .max_deliveries(2)
+        .timeout(10)
+    };
+    let handler = |_id: &str, _message: &Message| bail!("poison message");
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts()).unwrap();
+    let id = crate::produce(&mut redis, stream, &[("key", "value_1")]).unwrap();
+
+    // it leaves the message pending until max_deliveries is reached
+    consumer.consume().unwrap();
+    assert_eq!(consumer.failed_messages, 1);
+    assert!(!key_exists(&mut redis, dead_letter_stream));
+    let pending: redis::streams::StreamPendingReply = redis.xpending(stream, group_name).unwrap();
+    assert_eq!(pending.count(), 1);
+
+    // it moves the message to the dead-letter stream on the last delivery
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts()).unwrap();
+    consumer.consume().unwrap();
+
+    let pending: redis::streams::StreamPendingReply = redis.xpending(stream, group_name).unwrap();
+    assert_eq!(pending.count(), 0);
+
+    let dead_letters: redis::streams::StreamRangeReply =
+      redis.xrange_all(dead_letter_stream).unwrap();
+    assert_eq!(dead_letters.ids.len(), 1);
+    let dead_letter = &dead_letters.ids[0];
+    assert_eq!(dead_letter.get::<String>("key").unwrap(), "value_1");
+    assert_eq!(dead_letter.get::<String>("_dlq_id").unwrap(), id);
+    assert_eq!(dead_letter.get::<String>("_dlq_stream").unwrap(), *stream);
+    assert_eq!(
+      dead_letter.get::<String>("_dlq_group").unwrap(),
+      *group_name
+    );
+    assert_eq!(
+      dead_letter.get::<String>("_dlq_error").unwrap(),
+      "poison message"
+    );
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+    delete_stream(dead_letter_stream);
+  }
+
+  #[test]
+  fn test_max_deliveries_claim_idle() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let dead_letter_stream = &format!("{}:dead-letter", stream);
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    let handler = |_id: &str, _message: &Message| bail!("poison message");
+    let opts = ConsumerOpts::default()
+      .group(group_name, consumer_name)
+      .start_pos(StartPosition::StartOfStream)
+      .claim_idle(50)
+      .max_deliveries(2)
+      .timeout(10);
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+    crate::produce(&mut redis, stream, &[("key", "value_1")]).unwrap();
+    consumer.consume().unwrap();
+    assert_eq!(consumer.failed_messages, 1);
+
+    // the same consumer claims its failed message once idle, and moves it to
+    // the dead-letter stream
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    consumer.consume().unwrap();
+    assert_eq!(consumer.failed_messages, 2);
+    let pending: redis::streams::StreamPendingReply = redis.xpending(stream, group_name).unwrap();
+    assert_eq!(pending.count(), 0);
+    let dead_letters: usize = redis.xlen(dead_letter_stream).unwrap();
+    assert_eq!(dead_letters, 1);
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+    delete_stream(dead_letter_stream);
+  }
+
+  #[test]
+  fn test_outcomes() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let dead_letter_stream = &format!("{}:dead-letter", stream);
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    let mut ids = vec![];
+    for action in &["ack", "fail", "skip", "retry", "dead-letter", "stop", "ack"] {
+      ids.push(crate::produce(&mut redis, stream, &[("action", action)]).unwrap());
+    }
+
+    let handler = with_outcome(|_id: &str, message: &Message| {
+      let action = String::from_redis_value(message.get("action").unwrap())?;
+      Ok(match action.as_ref() {
+        "fail" => bail!("failed"),
+        "skip" => Outcome::Skip,
+        "retry" => Outcome::Retry,
+        "dead-letter" => Outcome::DeadLetter("invalid".to_string()),
+        "stop" => Outcome::Stop,
+        _ => Outcome::Ack,
+      })
+    });
+    let opts = ConsumerOpts::default()
+      .group(group_name, consumer_name)
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(10);
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+
+    // it keeps handling the batch after a failure, and stops on `Stop`
+    consumer.consume().unwrap();
+    assert!(consumer.stopped);
+    assert_eq!(consumer.handled_messages, 1);
+    assert_eq!(consumer.failed_messages, 1);
+
+    // failed, retried, stopped and unread messages are left pending
+    let pending: redis::streams::StreamPendingCountReply = redis
+      .xpending_count(stream, group_name, "-", "+", 10)
+      .unwrap();
+    let pending_ids: Vec<String> = pending.ids.into_iter().map(|p| p.id).collect();
+    assert_eq!(
+      pending_ids,
+      vec![
+        ids[1].clone(),
+        ids[3].clone(),
+        ids[5].clone(),
+        ids[6].clone()
+      ]
+    );
+
+    // dead-lettered messages are moved with the given reason
+    let dead_letters: redis::streams::StreamRangeReply =
+      redis.xrange_all(dead_letter_stream).unwrap();
+    assert_eq!(dead_letters.ids.len(), 1);
+    let dead_letter = &dead_letters.ids[0];
+    assert_eq!(dead_letter.get::<String>("_dlq_id").unwrap(), ids[4]);
+    assert_eq!(dead_letter.get::<String>("_dlq_error").unwrap(), "invalid");
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+    delete_stream(dead_letter_stream);
+  }
+
+  #[test]
+  fn test_context() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    let first_id = crate::produce(&mut redis, stream, &[("key", "value")]).unwrap();
+    let opts = ConsumerOpts::default()
+      .group(group_name, consumer_name)
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(10);
+
+    // a first run fails on the message, leaving it pending
+    let handler = |_id: &str, _message: &Message| bail!("failed");
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts.clone()).unwrap();
+    consumer.consume().unwrap();
+    drop(consumer);
+    let second_id = crate::produce(&mut redis, stream, &[("key", "value")]).unwrap();
+
+    let mut contexts = vec![];
+    let handler = with_context(|context: &Context, _message: &Message| {
+      contexts.push((
+        context.id().to_string(),
+        context.phase(),
+        context.delivery_count(),
+        context.timestamp(),
+        context.sequence(),
+      ));
+      assert_eq!(context.stream(), stream);
+      assert_eq!(context.group(), Some(group_name.as_str()));
+      assert_eq!(context.consumer(), Some(consumer_name.as_str()));
+      assert_eq!(context.opts().timeout, 10);
+      Ok(())
+    });
+    let mut consumer = Consumer::init_multi(&mut redis_c, &[stream], handler, opts).unwrap();
+    consumer.consume().unwrap();
+    consumer.consume().unwrap();
+    drop(consumer);
+
+    // it tells pending messages, delivered again, from new ones
+    let id_parts = |id: &str| -> (u64, u64) {
+      let (millis, seq) = id.split_once('-').unwrap();
+      (millis.parse().unwrap(), seq.parse().unwrap())
+    };
+    let (first_millis, first_seq) = id_parts(&first_id);
+    let (second_millis, second_seq) = id_parts(&second_id);
+    assert_eq!(
+      contexts,
+      vec![
+        (first_id, Phase::Pending, 2, first_millis, first_seq),
+        (second_id, Phase::New, 1, second_millis, second_seq),
+      ]
+    );
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+  }
+
+  #[test]
+  fn test_with_connection() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let counter = &format!("{}:count", stream);
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    for _ in 0..3 {
+      crate::produce(&mut redis, stream, &[("key", "value")]).unwrap();
+    }
+
+    let handler = with_connection(|redis: &mut Connection, _id: &str, _message: &Message| {
+      redis.incr::<_, _, ()>(counter, 1)?;
+      Ok(())
+    });
+    let opts = ConsumerOpts::default()
+      .group(group_name, consumer_name)
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(10);
+    let mut consumer = Consumer::init_multi(&mut redis_c, &[stream], handler, opts).unwrap();
+
+    // the handler writes with the connection of the consumer
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 3);
+    let count: usize = redis.get(counter).unwrap();
+    assert_eq!(count, 3);
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+    delete_stream(counter);
+  }
+
+  #[test]
+  fn test_with_transaction() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let counter = &format!("{}:count", stream);
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    let mut ids = vec![];
+    for action in &["ack", "retry", "fail", "ack"] {
+      ids.push(crate::produce(&mut redis, stream, &[("action", action)]).unwrap());
+    }
+
+    let handler = with_transaction(|tx: &mut Pipeline, _id: &str, message: &Message| {
+      tx.incr(counter, 1).ignore();
+      Ok(match message.str("action") {
+        Some("retry") => Outcome::Retry,
+        Some("fail") => bail!("failed"),
+        _ => Outcome::Ack,
+      })
+    });
+    let opts = ConsumerOpts::default()
+      .group(group_name, consumer_name)
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(10);
+    let mut consumer = Consumer::init_multi(&mut redis_c, &[stream], handler, opts).unwrap();
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 2);
+
+    // only the commands of acknowledged messages are run, along with their XACK
+    let count: usize = redis.get(counter).unwrap();
+    assert_eq!(count, 2);
+    let pending: redis::streams::StreamPendingCountReply = redis
+      .xpending_count(stream, group_name, "-", "+", 10)
+      .unwrap();
+    let pending_ids: Vec<String> = pending.ids.into_iter().map(|p| p.id).collect();
+    assert_eq!(pending_ids, vec![ids[1].clone(), ids[2].clone()]);
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+    delete_stream(counter);
+  }
+
+  #[test]
+  fn test_reconnect() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+    let redis_url =
+      std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let client = redis::Client::open(redis_url).unwrap();
+
+    let mut handled = vec![];
+    let handler = |id: &str, _message: &Message| {
+      handled.push(id.to_string());
+      Ok(())
+    };
+    let opts = ConsumerOpts::default()
+      .group(group_name, consumer_name)
+      .process_pending(false)
+      .reconnect(client, Backoff::default().max_attempts(3))
+      .timeout(10);
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+
+    // a message delivered to the consumer but never handled, as if the
+    // connection was lost before receiving the reply
+    let first_id = crate::produce(&mut redis, stream, &[("key", "value_1")]).unwrap();
+    let read: StreamReadReply = redis
+      .xread_options(
+        &[stream],
+        &[">"],
+        StreamReadOptions::default().group(group_name, consumer_name),
+      )
+      .unwrap();
+    assert_eq!(read.keys[0].ids.len(), 1);
+    let second_id = crate::produce(&mut redis, stream, &[("key", "value_2")]).unwrap();
+
+    // it reconnects instead of failing
+    let _: () = redis::cmd("QUIT").query(consumer.redis).unwrap();
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 0);
+
+    // and resumes from the pending messages
+    consumer.consume().unwrap();
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 2);
+    drop(consumer);
+    assert_eq!(handled, vec![first_id, second_id]);
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+  }
+
+  #[test]
+  fn test_run() {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    crate::produce(&mut redis, stream, &[("key", "value_1")]).unwrap();
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let stream_name = stream.clone();
+    let shutdown_c = shutdown.clone();
+    let child = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(200));
+      let mut redis = redis_connection();
+      crate::produce(&mut redis, &stream_name, &[("key", "value_2")]).unwrap();
+      thread::sleep(Duration::from_millis(200));
+      shutdown_c.store(true, Ordering::SeqCst);
+    });
+
+    let handler = |_id: &str, _message: &Message| Ok(());
+    let opts = ConsumerOpts::default()
+      .group(group_name, consumer_name)
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(50);
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+
+    // it consumes until shutdown is requested
+    let summary = consumer.run(&shutdown).unwrap();
+    child.join().unwrap();
+    assert_eq!(summary.handled_messages, 2);
+    assert!(summary.batches >= 2);
+
+    // it doesn't consume anything once shut down
+    let summary = consumer.run(&shutdown).unwrap();
+    assert_eq!(summary.handled_messages, 0);
+    assert_eq!(summary.batches, 0);
+
+    // and everything was acknowledged
+    let pending: redis::streams::StreamPendingReply = redis.xpending(stream, group_name).unwrap();
+    assert_eq!(pending.count(), 0);
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+  }
+
+  #[test]
+  fn test_run_stop() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    // it stops on the first delivery of the "stop" messages
+    let mut stopped_ids = std::collections::HashSet::new();
+    let handler = with_outcome(move |id: &str, message: &Message| {
+      Ok(match message.str("action") {
+        Some("stop") if stopped_ids.insert(id.to_string()) => Outcome::Stop,
+        _ => Outcome::Ack,
+      })
+    });
+    let opts = ConsumerOpts::default()
+      .group(group_name, consumer_name)
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(10);
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+    let shutdown = AtomicBool::new(false);
+    let pending_ids = |redis: &mut redis::Connection| -> Vec<String> {
+      let pending: redis::streams::StreamPendingCountReply = redis
+        .xpending_count(stream, group_name, "-", "+", 10)
+        .unwrap();
+      pending.ids.into_iter().map(|p| p.id).collect()
+    };
+
+    // it returns once the handler returned `Stop`, leaving the rest of the
+    // batch pending
+    let stop_id = crate::produce(&mut redis, stream, &[("action", "stop")]).unwrap();
+    let ack_id = crate::produce(&mut redis, stream, &[("action", "ack")]).unwrap();
+    let summary = consumer.run(&shutdown).unwrap();
+    assert!(consumer.stopped);
+    assert_eq!(summary.batches, 1);
+    assert_eq!(summary.handled_messages, 0);
+    assert_eq!(pending_ids(&mut redis), vec![stop_id, ack_id]);
+
+    // and handles them first when run again
+    let second_stop_id = crate::produce(&mut redis, stream, &[("action", "stop")]).unwrap();
+    let summary = consumer.run(&shutdown).unwrap();
+    assert!(consumer.stopped);
+    assert_eq!(summary.handled_messages, 2);
+    assert_eq!(pending_ids(&mut redis), vec![second_stop_id]);
+
+    // like consume
+    consumer.consume().unwrap();
+    assert!(!consumer.stopped);
+    assert_eq!(consumer.handled_messages, 3);
+    assert!(pending_ids(&mut redis).is_empty());
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+  }
+
   // note: `test_process_messages` is already tested by `test_consume`
 
   // note: `test_positions` is already tested by `test_consume` (but adding more
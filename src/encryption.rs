@@ -0,0 +1,726 @@
+//! Encryption of field values.
+//!
+//! Each cipher requires its own feature: `aes-gcm` or `chacha20poly1305`.
+//!
+//! A [`Producer`] with a [`Keyring`] encrypts every field value with the
+//! primary key of the keyring, and adds four marker fields: `_encryption`
+//! with the name of the cipher, `_key_id` with the id of the key,
+//! `_encrypted` with the comma separated names of the encrypted fields, and
+//! `_message_nonce` with a random nonce of the message.
+//! [`Consumer`]s with a keyring holding that key decrypt them before calling
+//! the handler, so keys can be rotated by adding a new primary key to the
+//! producers' keyring, once the consumers know it.
+//!
+//! Each value is encrypted with its own random nonce, and authenticated along
+//! with its field name, the stream name, the key id, the nonce of the message
+//! and the list of encrypted fields, so fields can't be dropped, swapped, or
+//! moved to another message or stream. The headers are neither encrypted nor
+//! authenticated, and a whole message can still be copied within its stream
+//! by anyone allowed to write to it: consumers of sensitive streams should be
+//! idempotent. Consumers move the messages failing
+//! authentication to the dead-letter stream, see [`DecryptError`], along with
+//! the messages holding unencrypted fields besides the headers, and the
+//! messages which aren't encrypted at all unless the keyring allows plaintext
+//! (see [`Keyring::allow_plaintext`]).
+//!
+//! ```no_run
+//! # #[cfg(feature = "aes-gcm")]
+//! # {
+//! use redis_stream::encryption::{Cipher, Keyring};
+//! use redis_stream::producer::{Producer, ProducerOpts};
+//!
+//! let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+//!   .expect("client")
+//!   .get_connection()
+//!   .expect("connection");
+//!
+//! let key = [0; 32]; // load it from a secret store
+//! let keyring = Keyring::new().primary("2024-01", Cipher::Aes256Gcm, key);
+//! let opts = ProducerOpts::default().keyring(keyring);
+//! let mut producer = Producer::init(&mut redis, "users", opts);
+//! producer.produce(&[("email", "jane@example.com")]).expect("produce");
+//! # }
+//! ```
+//!
+//! [`Producer`]: ../producer/struct.Producer.html
+//! [`Consumer`]: ../consumer/struct.Consumer.html
+use anyhow::{bail, Result};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use redis::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::consumer::{Message, MessageFields};
+use crate::headers::HEADERS;
+
+/// Reserved field holding the name of the cipher.
+pub const ENCRYPTION_FIELD: &str = "_encryption";
+/// Reserved field holding the id of the key.
+pub const KEY_ID_FIELD: &str = "_key_id";
+/// Reserved field holding the comma separated names of the encrypted fields.
+pub const ENCRYPTED_FIELD: &str = "_encrypted";
+/// Reserved field holding the random nonce of the message.
+pub const MESSAGE_NONCE_FIELD: &str = "_message_nonce";
+
+const NONCE_SIZE: usize = 12;
+// Alphanumeric characters of the nonce of a message (about 95 bits).
+const MESSAGE_NONCE_SIZE: usize = 16;
+
+/// Authenticated cipher, all using 256 bits keys and 96 bits nonces.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cipher {
+  /// AES-256-GCM (`aes-gcm` feature).
+  #[cfg(feature = "aes-gcm")]
+  Aes256Gcm,
+  /// ChaCha20-Poly1305 (`chacha20poly1305` feature).
+  #[cfg(feature = "chacha20poly1305")]
+  ChaCha20Poly1305,
+}
+
+impl Cipher {
+  /// Name of the cipher, written in the `_encryption` field.
+  pub fn name(&self) -> &'static str {
+    match *self {
+      #[cfg(feature = "aes-gcm")]
+      Cipher::Aes256Gcm => "aes-256-gcm",
+      #[cfg(feature = "chacha20poly1305")]
+      Cipher::ChaCha20Poly1305 => "chacha20-poly1305",
+    }
+  }
+
+  fn from_name(name: &str) -> Option<Self> {
+    match name {
+      #[cfg(feature = "aes-gcm")]
+      "aes-256-gcm" => Some(Cipher::Aes256Gcm),
+      #[cfg(feature = "chacha20poly1305")]
+      "chacha20-poly1305" => Some(Cipher::ChaCha20Poly1305),
+      _ => None,
+    }
+  }
+
+  // Returns the nonce followed by the ciphertext.
+  #[cfg_attr(
+    not(any(feature = "aes-gcm", feature = "chacha20poly1305")),
+    allow(unused_variables)
+  )]
+  fn encrypt(&self, key: &[u8; 32], data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    match *self {
+      #[cfg(feature = "aes-gcm")]
+      Cipher::Aes256Gcm => {
+        use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+        let cipher = aes_gcm::Aes256Gcm::new(key.into());
+        let nonce = aes_gcm::Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, Payload { msg: data, aad });
+        let ciphertext =
+          ciphertext.map_err(|_| anyhow::anyhow!("failed to encrypt with {}", self.name()))?;
+        Ok([&nonce[..], &ciphertext[..]].concat())
+      }
+      #[cfg(feature = "chacha20poly1305")]
+      Cipher::ChaCha20Poly1305 => {
+        use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+        let cipher = chacha20poly1305::ChaCha20Poly1305::new(key.into());
+        let nonce = chacha20poly1305::ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.encrypt(&nonce, Payload { msg: data, aad });
+        let ciphertext =
+          ciphertext.map_err(|_| anyhow::anyhow!("failed to encrypt with {}", self.name()))?;
+        Ok([&nonce[..], &ciphertext[..]].concat())
+      }
+    }
+  }
+
+  // Returns `None` when the data can't be authenticated.
+  #[cfg_attr(
+    not(any(feature = "aes-gcm", feature = "chacha20poly1305")),
+    allow(unused_variables)
+  )]
+  fn decrypt(&self, key: &[u8; 32], data: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < NONCE_SIZE {
+      return None;
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+    match *self {
+      #[cfg(feature = "aes-gcm")]
+      Cipher::Aes256Gcm => {
+        use aes_gcm::aead::{Aead, KeyInit, Payload};
+        let cipher = aes_gcm::Aes256Gcm::new(key.into());
+        let payload = Payload {
+          msg: ciphertext,
+          aad,
+        };
+        cipher.decrypt(nonce.into(), payload).ok()
+      }
+      #[cfg(feature = "chacha20poly1305")]
+      Cipher::ChaCha20Poly1305 => {
+        use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+        let cipher = chacha20poly1305::ChaCha20Poly1305::new(key.into());
+        let payload = Payload {
+          msg: ciphertext,
+          aad,
+        };
+        cipher.decrypt(nonce.into(), payload).ok()
+      }
+    }
+  }
+}
+
+/// Keys used to encrypt and decrypt messages, by id.
+#[derive(Clone, Default)]
+pub struct Keyring {
+  allow_plaintext: bool,
+  keys: HashMap<String, (Cipher, [u8; 32])>,
+  primary: Option<String>,
+}
+
+impl Keyring {
+  /// An empty keyring.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Hands the messages which aren't encrypted to the handler as they are
+  /// (default: `false`, they are moved to the dead-letter stream). Only useful
+  /// while the producers of a stream switch to encryption.
+  pub fn allow_plaintext(mut self, allow_plaintext: bool) -> Self {
+    self.allow_plaintext = allow_plaintext;
+    self
+  }
+
+  /// Adds a key only used to decrypt messages.
+  pub fn key(mut self, key_id: &str, cipher: Cipher, key: [u8; 32]) -> Self {
+    self.keys.insert(key_id.to_string(), (cipher, key));
+    self
+  }
+
+  /// Adds a key used to encrypt new messages (and to decrypt them).
+  pub fn primary(mut self, key_id: &str, cipher: Cipher, key: [u8; 32]) -> Self {
+    self.primary = Some(key_id.to_string());
+    self.key(key_id, cipher, key)
+  }
+
+  /// Encrypts the field values of a message of `stream` with the primary key,
+  /// and appends the marker fields.
+  pub fn encrypt_fields(
+    &self,
+    stream: &str,
+    fields: Vec<(Vec<u8>, Vec<u8>)>,
+  ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let (key_id, (cipher, key)) = match &self.primary {
+      Some(key_id) => (key_id, &self.keys[key_id]),
+      None => bail!("no primary key in the keyring"),
+    };
+
+    let mut names = vec![];
+    for (name, _) in &fields {
+      match std::str::from_utf8(name) {
+        Ok(name) if !name.is_empty() && !name.contains(',') => names.push(name.to_string()),
+        _ => bail!("field names must be non-empty UTF-8 without commas to be encrypted"),
+      }
+    }
+
+    let field_list = field_list(&names);
+    let message_nonce: String = thread_rng()
+      .sample_iter(&Alphanumeric)
+      .take(MESSAGE_NONCE_SIZE)
+      .map(char::from)
+      .collect();
+    let mut encrypted = vec![];
+    for (name, (_, value)) in names.iter().zip(fields) {
+      let aad = aad(stream, key_id, &message_nonce, &field_list, name);
+      let value = cipher.encrypt(key, &value, &aad)?;
+      encrypted.push((name.clone().into_bytes(), value));
+    }
+
+    encrypted.push((ENCRYPTION_FIELD.into(), cipher.name().into()));
+    encrypted.push((KEY_ID_FIELD.into(), key_id.clone().into_bytes()));
+    encrypted.push((ENCRYPTED_FIELD.into(), names.join(",").into_bytes()));
+    encrypted.push((MESSAGE_NONCE_FIELD.into(), message_nonce.into_bytes()));
+    Ok(encrypted)
+  }
+
+  /// Returns a copy of `message` (read from `stream`) with its encrypted
+  /// fields decrypted and the marker fields removed, or `None` if it isn't
+  /// encrypted and the keyring allows plaintext.
+  pub fn decrypt_message(
+    &self,
+    stream: &str,
+    message: &Message,
+  ) -> Result<Option<Message>, DecryptError> {
+    decrypt_message(Some(self), stream, message)
+  }
+}
+
+// Keys are left out.
+impl fmt::Debug for Keyring {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut key_ids: Vec<&String> = self.keys.keys().collect();
+    key_ids.sort();
+    f.debug_struct("Keyring")
+      .field("allow_plaintext", &self.allow_plaintext)
+      .field("keys", &key_ids)
+      .field("primary", &self.primary)
+      .finish()
+  }
+}
+
+/// Error returned when an encrypted message can't be decrypted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecryptError {
+  /// A field doesn't match its authentication tag: it was tampered with, or
+  /// encrypted with another key under the same id.
+  Authentication { field: String, key_id: String },
+  /// The marker fields are missing or invalid.
+  Invalid(String),
+  /// The keyring doesn't hold the key (or its cipher isn't enabled). Consumers
+  /// fail with it instead of dead-lettering the message, see
+  /// `Consumer::consume`.
+  UnknownKey(String),
+  /// The message isn't encrypted, and the keyring doesn't allow plaintext.
+  Unencrypted,
+}
+
+impl fmt::Display for DecryptError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DecryptError::Authentication { field, key_id } => write!(
+        f,
+        "failed to authenticate field `{}` encrypted with key `{}`",
+        field, key_id
+      ),
+      DecryptError::Invalid(reason) => write!(f, "invalid encrypted message: {}", reason),
+      DecryptError::UnknownKey(key_id) => write!(f, "unknown encryption key `{}`", key_id),
+      DecryptError::Unencrypted => write!(f, "message is not encrypted"),
+    }
+  }
+}
+
+impl std::error::Error for DecryptError {}
+
+/// Decrypts `message` with the `keyring` if it is encrypted (see
+/// [`Keyring::decrypt_message`]).
+pub(crate) fn decrypt_message(
+  keyring: Option<&Keyring>,
+  stream: &str,
+  message: &Message,
+) -> Result<Option<Message>, DecryptError> {
+  let cipher = match (message.get(ENCRYPTION_FIELD), keyring) {
+    (Some(_), _) => message.str(ENCRYPTION_FIELD).unwrap_or(""),
+    (None, Some(keyring)) if !keyring.allow_plaintext => return Err(DecryptError::Unencrypted),
+    (None, _) => return Ok(None),
+  };
+  let key_id = message
+    .str(KEY_ID_FIELD)
+    .ok_or_else(|| DecryptError::Invalid(format!("missing field `{}`", KEY_ID_FIELD)))?;
+  let message_nonce = message
+    .str(MESSAGE_NONCE_FIELD)
+    .ok_or_else(|| DecryptError::Invalid(format!("missing field `{}`", MESSAGE_NONCE_FIELD)))?;
+  let (cipher, key) = match keyring.and_then(|keyring| keyring.keys.get(key_id)) {
+    Some((key_cipher, key)) if Cipher::from_name(cipher) == Some(*key_cipher) => (key_cipher, key),
+    _ => return Err(DecryptError::UnknownKey(key_id.to_string())),
+  };
+
+  let fields: Vec<String> = message
+    .str(ENCRYPTED_FIELD)
+    .unwrap_or("")
+    .split(',')
+    .filter(|field| !field.is_empty())
+    .map(str::to_string)
+    .collect();
+  // Only the headers are left readable by producers
+  let markers = [
+    ENCRYPTION_FIELD,
+    KEY_ID_FIELD,
+    ENCRYPTED_FIELD,
+    MESSAGE_NONCE_FIELD,
+  ];
+  if let Some(field) = message.keys().find(|field| {
+    !fields.contains(field)
+      && !markers.contains(&field.as_str())
+      && !HEADERS.contains(&field.as_str())
+  }) {
+    return Err(DecryptError::Invalid(format!(
+      "unencrypted field `{}`",
+      field
+    )));
+  }
+
+  let field_list = field_list(&fields);
+  let mut decrypted = HashMap::new();
+  for key in HEADERS.iter() {
+    if let Some(value) = message.get(*key) {
+      decrypted.insert(key.to_string(), value.clone());
+    }
+  }
+  for field in &fields {
+    let data = message
+      .bytes(field)
+      .ok_or_else(|| DecryptError::Invalid(format!("missing encrypted field `{}`", field)))?;
+    let data = cipher
+      .decrypt(
+        key,
+        data,
+        &aad(stream, key_id, message_nonce, &field_list, field),
+      )
+      .ok_or_else(|| DecryptError::Authentication {
+        field: field.to_string(),
+        key_id: key_id.to_string(),
+      })?;
+    decrypted.insert(field.to_string(), Value::Data(data));
+  }
+
+  Ok(Some(decrypted))
+}
+
+// Authenticated data of a field: binds its value to its name, the stream, the
+// key id, the nonce of the message and the names of all the encrypted fields
+// of the message (see `field_list`).
+fn aad(stream: &str, key_id: &str, message_nonce: &str, field_list: &str, field: &str) -> Vec<u8> {
+  format!(
+    "{}\n{}\n{}\n{}\n{}",
+    stream, key_id, message_nonce, field_list, field
+  )
+  .into_bytes()
+}
+
+// The sorted, comma separated names of the encrypted fields.
+fn field_list(fields: &[String]) -> String {
+  let mut fields = fields.to_vec();
+  fields.sort();
+  fields.join(",")
+}
+
+#[cfg(all(test, feature = "aes-gcm", feature = "chacha20poly1305"))]
+mod tests {
+  use super::*;
+  use crate::consumer::{Consumer, ConsumerOpts, StartPosition};
+  use crate::producer::{Producer, ProducerOpts};
+  use crate::test_helpers::*;
+  use redis::Commands;
+
+  #[test]
+  fn test_decrypt_message() {
+    let keyring = Keyring::new()
+      .key("old", Cipher::ChaCha20Poly1305, [1; 32])
+      .primary("new", Cipher::Aes256Gcm, [2; 32]);
+    let fields = vec![
+      (b"email".to_vec(), b"jane@example.com".to_vec()),
+      (b"name".to_vec(), b"Jane".to_vec()),
+    ];
+    let message = |fields: Vec<(Vec<u8>, Vec<u8>)>| -> Message {
+      fields
+        .into_iter()
+        .map(|(k, v)| (String::from_utf8(k).unwrap(), Value::Data(v)))
+        .collect()
+    };
+
+    // it round-trips, with random nonces
+    let encrypted = keyring.encrypt_fields("users", fields.clone()).unwrap();
+    assert_ne!(
+      encrypted,
+      keyring.encrypt_fields("users", fields.clone()).unwrap()
+    );
+    let mut encrypted = message(encrypted);
+    assert_eq!(encrypted.str(KEY_ID_FIELD), Some("new"));
+    assert_eq!(encrypted.str(ENCRYPTION_FIELD), Some("aes-256-gcm"));
+    let decrypted = keyring
+      .decrypt_message("users", &encrypted)
+      .unwrap()
+      .unwrap();
+    assert_eq!(decrypted, message(fields.clone()));
+
+    // it needs the key
+    let old_keyring = Keyring::new().primary("old", Cipher::ChaCha20Poly1305, [1; 32]);
+    assert_eq!(
+      old_keyring.decrypt_message("users", &encrypted),
+      Err(DecryptError::UnknownKey("new".to_string()))
+    );
+
+    // it detects dropped and added fields
+    let mut dropped = encrypted.clone();
+    dropped.remove("name");
+    dropped.insert(ENCRYPTED_FIELD.to_string(), Value::Data(b"email".to_vec()));
+    assert_eq!(
+      keyring.decrypt_message("users", &dropped),
+      Err(DecryptError::Authentication {
+        field: "email".to_string(),
+        key_id: "new".to_string()
+      })
+    );
+    let mut added = encrypted.clone();
+    added.insert("admin".to_string(), Value::Data(b"true".to_vec()));
+    assert_eq!(
+      keyring.decrypt_message("users", &added),
+      Err(DecryptError::Invalid(
+        "unencrypted field `admin`".to_string()
+      ))
+    );
+
+    // it rejects plaintext messages, unless allowed
+    let plaintext = message(fields.clone());
+    assert_eq!(
+      keyring.decrypt_message("users", &plaintext),
+      Err(DecryptError::Unencrypted)
+    );
+    let keyring_plaintext = keyring.clone().allow_plaintext(true);
+    assert_eq!(
+      keyring_plaintext.decrypt_message("users", &plaintext),
+      Ok(None)
+    );
+
+    // it detects messages moved to another stream, and fields moved from
+    // another message
+    assert_eq!(
+      keyring.decrypt_message("admins", &encrypted),
+      Err(DecryptError::Authentication {
+        field: "email".to_string(),
+        key_id: "new".to_string()
+      })
+    );
+    let other = message(keyring.encrypt_fields("users", fields.clone()).unwrap());
+    let mut spliced = encrypted.clone();
+    spliced.insert("name".to_string(), other["name"].clone());
+    assert_eq!(
+      keyring.decrypt_message("users", &spliced),
+      Err(DecryptError::Authentication {
+        field: "name".to_string(),
+        key_id: "new".to_string()
+      })
+    );
+
+    // it detects tampering
+    let mut data = encrypted.bytes("email").unwrap().to_vec();
+    data[NONCE_SIZE] ^= 1;
+    encrypted.insert("email".to_string(), Value::Data(data));
+    assert_eq!(
+      keyring.decrypt_message("users", &encrypted),
+      Err(DecryptError::Authentication {
+        field: "email".to_string(),
+        key_id: "new".to_string()
+      })
+    );
+  }
+
+  #[test]
+  fn test_consume_encrypted() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let dead_letter_stream = &format!("{}:dead-letter", stream);
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+    let old_key = Keyring::new().primary("old", Cipher::ChaCha20Poly1305, [1; 32]);
+    let new_key = Keyring::new()
+      .key("old", Cipher::ChaCha20Poly1305, [1; 32])
+      .primary("new", Cipher::Aes256Gcm, [2; 32]);
+
+    // producers rotating keys
+    for keyring in &[old_key, new_key.clone()] {
+      let opts = ProducerOpts::default().keyring(keyring.clone());
+      let mut producer = Producer::init(&mut redis, stream, opts);
+      producer.produce(&[("email", "jane@example.com")]).unwrap();
+    }
+    let entries: redis::streams::StreamRangeReply = redis.xrange_all(stream).unwrap();
+    assert_ne!(entries.ids[0].map.str("email"), Some("jane@example.com"));
+
+    // and a producer with a compromised key
+    let forged = Keyring::new().primary("new", Cipher::Aes256Gcm, [3; 32]);
+    let opts = ProducerOpts::default().keyring(forged);
+    let mut producer = Producer::init(&mut redis, stream, opts);
+    producer
+      .produce(&[("email", "mallory@example.com")])
+      .unwrap();
+
+    // or without encryption
+    crate::produce(&mut redis, stream, &[("email", "mallory@example.com")]).unwrap();
+
+    let mut emails = vec![];
+    let handler = |_id: &str, message: &Message| {
+      emails.push(message.str("email").unwrap().to_string());
+      assert_eq!(message.len(), 1);
+      Ok(())
+    };
+    let opts = ConsumerOpts::default()
+      .start_pos(StartPosition::StartOfStream)
+      .keyring(new_key)
+      .timeout(10);
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+
+    // it decrypts with both keys, and dead-letters forged and plaintext
+    // messages
+    consumer.consume().unwrap();
+    drop(consumer);
+    assert_eq!(emails, vec!["jane@example.com", "jane@example.com"]);
+
+    let dead_letters: redis::streams::StreamRangeReply =
+      redis.xrange_all(dead_letter_stream).unwrap();
+    assert_eq!(dead_letters.ids.len(), 2);
+    assert_eq!(
+      dead_letters.ids[0].get::<String>("_dlq_error").unwrap(),
+      "failed to authenticate field `email` encrypted with key `new`"
+    );
+    assert_eq!(
+      dead_letters.ids[1].get::<String>("_dlq_error").unwrap(),
+      "message is not encrypted"
+    );
+
+    delete_stream(stream);
+    delete_stream(dead_letter_stream);
+  }
+
+  #[test]
+  fn test_consume_unknown_key() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let group_name = &format!("test-group-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+    let old_key = Keyring::new().primary("old", Cipher::ChaCha20Poly1305, [1; 32]);
+    let new_key = Keyring::new()
+      .key("old", Cipher::ChaCha20Poly1305, [1; 32])
+      .primary("new", Cipher::Aes256Gcm, [2; 32]);
+
+    // a producer with a key not deployed to the consumers yet
+    for keyring in &[new_key.clone(), old_key.clone()] {
+      let opts = ProducerOpts::default().keyring(keyring.clone());
+      let mut producer = Producer::init(&mut redis, stream, opts);
+      producer.produce(&[("email", "jane@example.com")]).unwrap();
+    }
+
+    let handler = |_id: &str, _message: &Message| Ok(());
+    let opts = ConsumerOpts::default()
+      .group(group_name, "worker.1")
+      .start_pos(StartPosition::StartOfStream)
+      .keyring(old_key)
+      .timeout(10);
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+
+    // it fails with a typed error, without counting the message as failed
+    for _ in 0..2 {
+      let err = consumer.consume().unwrap_err();
+      assert_eq!(
+        err.downcast_ref::<DecryptError>(),
+        Some(&DecryptError::UnknownKey("new".to_string()))
+      );
+      assert_eq!(consumer.failed_messages, 0);
+      assert_eq!(consumer.handled_messages, 0);
+    }
+
+    // and handles the message and the rest of its batch once the key is known
+    consumer.keyring = Some(new_key);
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 2);
+    drop(consumer);
+    let pending: redis::streams::StreamPendingReply = redis.xpending(stream, group_name).unwrap();
+    assert_eq!(pending.count(), 0);
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+  }
+
+  #[test]
+  fn test_batch_encrypted() {
+    use crate::batch::BatchConsumer;
+
+    let stream = &format!("test-stream-{}", random_string(25));
+    let dead_letter_stream = &format!("{}:dead-letter", stream);
+    let group_name = &format!("test-group-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+    let keyring = Keyring::new().primary("new", Cipher::Aes256Gcm, [2; 32]);
+
+    let opts = ProducerOpts::default().keyring(keyring.clone());
+    Producer::init(&mut redis, stream, opts)
+      .produce(&[("email", "jane@example.com")])
+      .unwrap();
+    crate::produce(&mut redis, stream, &[("email", "mallory@example.com")]).unwrap();
+
+    // it hands decrypted messages to the handler, and dead-letters the others
+    let mut emails = vec![];
+    let handler = |_stream: &str, messages: &[(String, Message)]| {
+      for (_, message) in messages {
+        emails.push(message.str("email").unwrap().to_string());
+      }
+      Ok(messages.iter().map(|(id, _)| id.clone()).collect())
+    };
+    let opts = ConsumerOpts::default()
+      .group(group_name, "worker.1")
+      .start_pos(StartPosition::StartOfStream)
+      .keyring(keyring)
+      .timeout(10);
+    let mut consumer = BatchConsumer::init(&mut redis_c, stream, handler, opts).unwrap();
+    consumer.consume().unwrap();
+    drop(consumer);
+    assert_eq!(emails, vec!["jane@example.com"]);
+
+    let dead_letters: usize = redis.xlen(dead_letter_stream).unwrap();
+    assert_eq!(dead_letters, 1);
+    let pending: redis::streams::StreamPendingReply = redis.xpending(stream, group_name).unwrap();
+    assert_eq!(pending.count(), 0);
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+    delete_stream(dead_letter_stream);
+  }
+
+  #[cfg(feature = "aio")]
+  #[tokio::test]
+  async fn test_async_encrypted() {
+    use crate::aio::{deliveries, AsyncConsumer};
+    use futures::StreamExt;
+
+    let stream = &format!("test-stream-{}", random_string(25));
+    let dead_letter_stream = &format!("{}:dead-letter", stream);
+    let mut redis = redis_connection();
+    let keyring = Keyring::new().primary("new", Cipher::Aes256Gcm, [2; 32]);
+
+    let opts = ProducerOpts::default().keyring(keyring.clone());
+    let mut producer = Producer::init(redis_connection(), stream, opts);
+    producer.produce(&[("email", "jane@example.com")]).unwrap();
+    crate::produce(&mut redis, stream, &[("email", "mallory@example.com")]).unwrap();
+    producer.produce(&[("email", "john@example.com")]).unwrap();
+
+    let redis_url =
+      std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    let redis_a = redis::Client::open(redis_url)
+      .unwrap()
+      .get_multiplexed_tokio_connection()
+      .await
+      .unwrap();
+    let opts = || {
+      ConsumerOpts::default()
+        .start_pos(StartPosition::StartOfStream)
+        .keyring(keyring.clone())
+        .timeout(10)
+    };
+
+    // the async consumer decrypts messages, and dead-letters the others
+    let (tx, rx) = std::sync::mpsc::channel();
+    let handler = |_id: String, message: Message| {
+      let tx = tx.clone();
+      async move {
+        tx.send(message.str("email").unwrap().to_string())?;
+        Ok(())
+      }
+    };
+    let mut consumer = AsyncConsumer::init(redis_a.clone(), stream, handler, opts())
+      .await
+      .unwrap();
+    consumer.consume().await.unwrap();
+    let emails: Vec<String> = rx.try_iter().collect();
+    assert_eq!(emails, vec!["jane@example.com", "john@example.com"]);
+    let dead_letters: usize = redis.xlen(dead_letter_stream).unwrap();
+    assert_eq!(dead_letters, 1);
+
+    // and so do deliveries
+    let emails: Vec<String> = deliveries(redis_a.clone(), redis_a, stream, opts())
+      .await
+      .unwrap()
+      .take(2)
+      .map(|delivery| delivery.unwrap().body().str("email").unwrap().to_string())
+      .collect()
+      .await;
+    assert_eq!(emails, vec!["jane@example.com", "john@example.com"]);
+    let dead_letters: usize = redis.xlen(dead_letter_stream).unwrap();
+    assert_eq!(dead_letters, 2);
+
+    delete_stream(stream);
+    delete_stream(dead_letter_stream);
+  }
+}
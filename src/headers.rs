@@ -0,0 +1,309 @@
+//! Message headers: metadata stored in reserved fields, next to the body.
+//!
+//! Headers are set by a [`Producer`], either for every message with
+//! [`ProducerOpts::headers`], or per message with
+//! [`Producer::produce_with_headers`]. The `_produced_at` header is stamped
+//! by the producer whenever headers are set. Headers are neither compressed
+//! nor encrypted.
+//!
+//! Handlers wrapped with [`with_delivery`] receive a [`Delivery`], with the
+//! headers separated from the body.
+//!
+//! ```no_run
+//! use redis_stream::headers::Headers;
+//! use redis_stream::producer::{Producer, ProducerOpts};
+//!
+//! let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+//!   .expect("client")
+//!   .get_connection()
+//!   .expect("connection");
+//!
+//! let headers = Headers::new()
+//!   .content_type("text/plain")
+//!   .producer_id("billing-1");
+//! let opts = ProducerOpts::default().headers(headers);
+//! let mut producer = Producer::init(&mut redis, "invoices", opts);
+//!
+//! let headers = Headers::new().correlation_id("order-42");
+//! producer
+//!   .produce_with_headers(&headers, &[("amount", "42")])
+//!   .expect("produce");
+//! ```
+//!
+//! [`Producer`]: ../producer/struct.Producer.html
+//! [`ProducerOpts::headers`]: ../types/struct.ProducerOpts.html#method.headers
+//! [`Producer::produce_with_headers`]: ../producer/struct.Producer.html#method.produce_with_headers
+//! [`with_delivery`]: ../consumer/fn.with_delivery.html
+//! [`Delivery`]: ../consumer/struct.Delivery.html
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::consumer::{Message, MessageFields};
+
+/// Reserved field holding the content type of the body.
+pub const CONTENT_TYPE_HEADER: &str = "_content_type";
+/// Reserved field holding the id correlating related messages.
+pub const CORRELATION_ID_HEADER: &str = "_correlation_id";
+/// Reserved field holding the production time, in milliseconds since the Unix
+/// epoch.
+pub const PRODUCED_AT_HEADER: &str = "_produced_at";
+/// Reserved field holding the id of the producer.
+pub const PRODUCER_ID_HEADER: &str = "_producer_id";
+/// Reserved field holding the version of the schema of the body.
+pub const SCHEMA_VERSION_HEADER: &str = "_schema_version";
+
+pub(crate) const HEADERS: [&str; 5] = [
+  CONTENT_TYPE_HEADER,
+  CORRELATION_ID_HEADER,
+  PRODUCED_AT_HEADER,
+  PRODUCER_ID_HEADER,
+  SCHEMA_VERSION_HEADER,
+];
+
+/// Metadata of a message.
+///
+/// ```
+/// use redis_stream::headers::Headers;
+///
+/// let headers = Headers::new()
+///   .content_type("application/json")
+///   .schema_version(2);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Headers {
+  pub content_type: Option<String>,
+  pub correlation_id: Option<String>,
+  pub produced_at: Option<SystemTime>,
+  pub producer_id: Option<String>,
+  pub schema_version: Option<u32>,
+}
+
+impl Headers {
+  /// Empty headers.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Content type of the body (e.g. `application/json`).
+  pub fn content_type(mut self, content_type: &str) -> Self {
+    self.content_type = Some(content_type.to_string());
+    self
+  }
+
+  /// Id correlating related messages (e.g. a request and its reply).
+  pub fn correlation_id(mut self, correlation_id: &str) -> Self {
+    self.correlation_id = Some(correlation_id.to_string());
+    self
+  }
+
+  /// Production time (default: stamped by the producer).
+  pub fn produced_at(mut self, produced_at: SystemTime) -> Self {
+    self.produced_at = Some(produced_at);
+    self
+  }
+
+  /// Id of the producer (e.g. the name of the service and instance).
+  pub fn producer_id(mut self, producer_id: &str) -> Self {
+    self.producer_id = Some(producer_id.to_string());
+    self
+  }
+
+  /// Version of the schema of the body.
+  pub fn schema_version(mut self, schema_version: u32) -> Self {
+    self.schema_version = Some(schema_version);
+    self
+  }
+
+  /// Returns these headers, overridden by the ones set in `headers`.
+  pub fn merge(&self, headers: &Headers) -> Headers {
+    Headers {
+      content_type: headers
+        .content_type
+        .clone()
+        .or_else(|| self.content_type.clone()),
+      correlation_id: headers
+        .correlation_id
+        .clone()
+        .or_else(|| self.correlation_id.clone()),
+      produced_at: headers.produced_at.or(self.produced_at),
+      producer_id: headers
+        .producer_id
+        .clone()
+        .or_else(|| self.producer_id.clone()),
+      schema_version: headers.schema_version.or(self.schema_version),
+    }
+  }
+
+  /// The reserved fields of the headers which are set.
+  pub fn to_fields(&self) -> Vec<(&'static str, String)> {
+    let produced_at = self.produced_at.map(|produced_at| {
+      let millis = produced_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+      millis.to_string()
+    });
+    let fields = vec![
+      (CONTENT_TYPE_HEADER, self.content_type.clone()),
+      (CORRELATION_ID_HEADER, self.correlation_id.clone()),
+      (PRODUCED_AT_HEADER, produced_at),
+      (PRODUCER_ID_HEADER, self.producer_id.clone()),
+      (
+        SCHEMA_VERSION_HEADER,
+        self.schema_version.map(|v| v.to_string()),
+      ),
+    ];
+    fields
+      .into_iter()
+      .filter_map(|(key, value)| value.map(|value| (key, value)))
+      .collect()
+  }
+
+  /// Splits `message` into its headers and its body.
+  ///
+  /// Fails with the name of the first header which is not valid.
+  pub fn split(message: &Message) -> Result<(Headers, Message), String> {
+    let header = |key: &str| match message.get(key) {
+      Some(_) => message
+        .str(key)
+        .map(|value| Some(value.to_string()))
+        .ok_or_else(|| format!("invalid header `{}`", key)),
+      None => Ok(None),
+    };
+    let produced_at = match header(PRODUCED_AT_HEADER)? {
+      Some(millis) => match millis.parse() {
+        Ok(millis) => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        Err(_) => return Err(format!("invalid header `{}`", PRODUCED_AT_HEADER)),
+      },
+      None => None,
+    };
+    let schema_version = match header(SCHEMA_VERSION_HEADER)? {
+      Some(version) => match version.parse() {
+        Ok(version) => Some(version),
+        Err(_) => return Err(format!("invalid header `{}`", SCHEMA_VERSION_HEADER)),
+      },
+      None => None,
+    };
+    let headers = Headers {
+      content_type: header(CONTENT_TYPE_HEADER)?,
+      correlation_id: header(CORRELATION_ID_HEADER)?,
+      produced_at,
+      producer_id: header(PRODUCER_ID_HEADER)?,
+      schema_version,
+    };
+
+    let mut body = message.clone();
+    for key in HEADERS.iter() {
+      body.remove(*key);
+    }
+    Ok((headers, body))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::consumer::{with_delivery, Consumer, ConsumerOpts, StartPosition};
+  use crate::producer::{Producer, ProducerOpts};
+  use crate::test_helpers::*;
+  use redis::{Commands, Value};
+
+  #[test]
+  fn test_split() {
+    let produced_at = UNIX_EPOCH + Duration::from_millis(1_600_000_000_000);
+    let headers = Headers::new()
+      .content_type("text/plain")
+      .produced_at(produced_at)
+      .schema_version(2);
+    let mut message: Message = headers
+      .to_fields()
+      .into_iter()
+      .map(|(key, value)| (key.to_string(), Value::Data(value.into_bytes())))
+      .collect();
+    message.insert("key".to_string(), Value::Data(b"value".to_vec()));
+
+    // it round-trips the headers set, and keeps the other fields as the body
+    let (split, body) = Headers::split(&message).unwrap();
+    assert_eq!(split, headers);
+    assert_eq!(body.len(), 1);
+    assert_eq!(body.str("key"), Some("value"));
+
+    // it fails on invalid headers
+    message.insert(
+      SCHEMA_VERSION_HEADER.to_string(),
+      Value::Data(b"v2".to_vec()),
+    );
+    assert_eq!(
+      Headers::split(&message).unwrap_err(),
+      "invalid header `_schema_version`"
+    );
+  }
+
+  #[test]
+  fn test_merge() {
+    let defaults = Headers::new()
+      .content_type("text/plain")
+      .producer_id("producer-1");
+    let headers = Headers::new()
+      .content_type("application/json")
+      .correlation_id("42");
+    assert_eq!(
+      defaults.merge(&headers),
+      Headers::new()
+        .content_type("application/json")
+        .correlation_id("42")
+        .producer_id("producer-1")
+    );
+  }
+
+  #[test]
+  fn test_consume_headers() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let dead_letter_stream = &format!("{}:dead-letter", stream);
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    let opts = ProducerOpts::default().headers(Headers::new().producer_id("producer-1"));
+    let mut producer = Producer::init(&mut redis, stream, opts);
+    let before = SystemTime::now() - Duration::from_secs(1);
+    producer
+      .produce_with_headers(&Headers::new().correlation_id("42"), &[("key", "value")])
+      .unwrap();
+    // invalid headers
+    crate::produce(&mut redis, stream, &[(PRODUCED_AT_HEADER, "yesterday")]).unwrap();
+
+    let mut deliveries = vec![];
+    let handler = with_delivery(|delivery| {
+      deliveries.push((delivery.headers().clone(), delivery.body().clone()));
+      Ok(())
+    });
+    let opts = ConsumerOpts::default()
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(10);
+    let mut consumer = Consumer::init_multi(&mut redis_c, &[stream], handler, opts).unwrap();
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 1);
+    drop(consumer);
+
+    // it separates the headers, stamped with the production time, from the body
+    assert_eq!(deliveries.len(), 1);
+    let (headers, body) = &deliveries[0];
+    assert_eq!(headers.correlation_id.as_deref(), Some("42"));
+    assert_eq!(headers.producer_id.as_deref(), Some("producer-1"));
+    assert_eq!(headers.content_type, None);
+    let produced_at = headers.produced_at.unwrap();
+    assert!(produced_at > before && produced_at <= SystemTime::now());
+    assert_eq!(body.len(), 1);
+    assert_eq!(body.str("key"), Some("value"));
+
+    // it dead-letters messages with invalid headers
+    let entries: redis::streams::StreamRangeReply = redis.xrange_all(dead_letter_stream).unwrap();
+    assert_eq!(entries.ids.len(), 1);
+    assert_eq!(
+      entries.ids[0].map.str("_dlq_error"),
+      Some("invalid header `_produced_at`")
+    );
+
+    delete_stream(stream);
+    delete_stream(dead_letter_stream);
+  }
+}
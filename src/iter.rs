@@ -0,0 +1,311 @@
+//! Iterator over the messages read by a [`Consumer`], see [`Consumer::iter`].
+//!
+//! **The iteration ends when a read times out without any new message**, after
+//! the `timeout` of the consumer, so a long-running worker calls
+//! [`Consumer::iter`] again in a loop.
+//!
+//! Each blocking read fills a buffer with a batch of messages, which are then
+//! yielded one by one as [`Entry`] items. The messages left in the buffer when
+//! the iterator is dropped (by `take`, or a `break`) are yielded by the next
+//! iterator of the consumer, or handled by the next [`Consumer::consume`].
+//!
+//! Messages are acknowledged (group consumers) automatically when the next
+//! entry is pulled, so adapters like `take`, `filter` or `map` just work. The
+//! last entry pulled when the iterator is dropped may not have been handled
+//! (the loop may have been left because it failed): unless it was
+//! acknowledged with [`Entry::ack`], it is given back to the consumer, and
+//! yielded again by its next iterator (or handled by [`Consumer::consume`]).
+//! With [`Entries::manual_ack`], only the entries acknowledged with
+//! [`Entry::ack`] are, before the next one is pulled.
+//!
+//! The acknowledgements sent when the iterator is dropped can't report their
+//! errors: if the connection failed, these messages are left pending, and
+//! delivered again once claimed (see [`ConsumerOpts::claim_idle`]).
+//!
+//! Messages are decrypted and decompressed like for handlers, but idle
+//! messages are not claimed (see [`Consumer::consume`]).
+//!
+//! [`Consumer`]: ../consumer/struct.Consumer.html
+//! [`Consumer::iter`]: ../consumer/struct.Consumer.html#method.iter
+//! [`Consumer::consume`]: ../consumer/struct.Consumer.html#method.consume
+//! [`ConsumerOpts::claim_idle`]: ../types/struct.ConsumerOpts.html#method.claim_idle
+use anyhow::Result;
+use redis::streams::StreamId;
+use redis::Connection;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::consumer::{open_message, AsConnection, Consumer, ContextHandler, Message};
+
+type Acks = Rc<RefCell<Vec<(String, String)>>>;
+
+/// A message yielded by [`Entries`].
+#[derive(Debug)]
+pub struct Entry {
+  pub id: String,
+  pub message: Message,
+  pub stream: String,
+  acks: Acks,
+}
+
+impl Entry {
+  /// Acknowledges the message before the next one is pulled (or when the
+  /// iterator is dropped).
+  pub fn ack(&self) {
+    let ack = (self.stream.clone(), self.id.clone());
+    let mut acks = self.acks.borrow_mut();
+    if !acks.contains(&ack) {
+      acks.push(ack);
+    }
+  }
+}
+
+impl From<Entry> for (String, Message) {
+  fn from(entry: Entry) -> Self {
+    (entry.id, entry.message)
+  }
+}
+
+/// Iterator over the messages read by a [`Consumer`], yielding an error when
+/// reading or acknowledging fails.
+///
+/// [`Consumer`]: ../consumer/struct.Consumer.html
+pub struct Entries<'c, H, R = Connection>
+where
+  H: ContextHandler<R::Connection>,
+  R: AsConnection,
+{
+  acks: Acks,
+  auto_ack: bool,
+  consumer: &'c mut Consumer<H, R>,
+  // Last message pulled, with the index of its stream, acknowledged with the
+  // next pull if `auto_ack`.
+  last: Option<(usize, StreamId)>,
+}
+
+impl<'c, H, R> Entries<'c, H, R>
+where
+  H: ContextHandler<R::Connection>,
+  R: AsConnection,
+{
+  pub(crate) fn new(consumer: &'c mut Consumer<H, R>) -> Self {
+    Entries {
+      acks: Rc::new(RefCell::new(vec![])),
+      auto_ack: true,
+      consumer,
+      last: None,
+    }
+  }
+
+  /// Only acknowledge the entries acknowledged with [`Entry::ack`].
+  pub fn manual_ack(mut self) -> Self {
+    self.auto_ack = false;
+    self
+  }
+
+  /// XACK the acknowledged entries.
+  fn flush_acks(&mut self) -> Result<()> {
+    let acks: Vec<(String, String)> = self.acks.borrow_mut().drain(..).collect();
+    for (stream, id) in acks {
+      self.consumer.ack(&stream, &id)?;
+      self.consumer.handled_messages += 1;
+    }
+    Ok(())
+  }
+
+  /// Reads the next batch into the buffer, and tells whether it read any
+  /// message.
+  fn fill(&mut self) -> Result<bool> {
+    loop {
+      let stream_results = self.consumer.read()?;
+      let mut switched_to_new = false;
+      for stream in stream_results.keys {
+        let index = match self
+          .consumer
+          .streams
+          .iter()
+          .position(|name| name == &stream.key)
+        {
+          Some(index) => index,
+          None => continue,
+        };
+        if self.consumer.switch_to_new(index, stream.ids.is_empty()) {
+          switched_to_new = true;
+          continue;
+        }
+        self
+          .consumer
+          .buffer
+          .extend(stream.ids.into_iter().map(|message| (index, message)));
+      }
+
+      if !self.consumer.buffer.is_empty() {
+        return Ok(true);
+      }
+      if !switched_to_new {
+        return Ok(false);
+      }
+    }
+  }
+
+  fn next_entry(&mut self) -> Result<Option<Entry>> {
+    if let Some((index, message)) = self.last.take() {
+      let ack = (self.consumer.streams[index].clone(), message.id);
+      let mut acks = self.acks.borrow_mut();
+      if !acks.contains(&ack) {
+        acks.push(ack);
+      }
+    }
+    self.flush_acks()?;
+
+    loop {
+      let (index, message) = match self.consumer.buffer.pop_front() {
+        Some(message) => message,
+        None if self.fill()? => continue,
+        None => return Ok(None),
+      };
+      let stream = self.consumer.streams[index].clone();
+
+      // Keep next_pos if we are in a consumer-group and it's already `>`
+      if self.consumer.next_pos[index] != ">" {
+        // or take the last id
+        self.consumer.next_pos[index] = message.id.clone();
+      }
+
+      let opened = match open_message(self.consumer.keyring.as_ref(), &stream, &message.map)? {
+        Ok(opened) => opened,
+        Err(reason) => {
+          self
+            .consumer
+            .dead_letter(&stream, &message.id, &message.map, &reason)?;
+          continue;
+        }
+      };
+      if self.auto_ack {
+        self.last = Some((index, message.clone()));
+      }
+      let entry = Entry {
+        id: message.id,
+        message: opened.unwrap_or(message.map),
+        stream,
+        acks: self.acks.clone(),
+      };
+      return Ok(Some(entry));
+    }
+  }
+}
+
+impl<'c, H, R> Iterator for Entries<'c, H, R>
+where
+  H: ContextHandler<R::Connection>,
+  R: AsConnection,
+{
+  type Item = Result<Entry>;
+
+  fn next(&mut self) -> Option<Result<Entry>> {
+    self.next_entry().transpose()
+  }
+}
+
+impl<'c, H, R> Drop for Entries<'c, H, R>
+where
+  H: ContextHandler<R::Connection>,
+  R: AsConnection,
+{
+  fn drop(&mut self) {
+    // The last message is yielded again by the next iterator, unless it was
+    // acknowledged explicitly
+    if let Some((index, message)) = self.last.take() {
+      let ack = (self.consumer.streams[index].clone(), message.id.clone());
+      if !self.acks.borrow().contains(&ack) {
+        self.consumer.buffer.push_front((index, message));
+      }
+    }
+    // Errors are lost: unacknowledged messages are delivered again anyway
+    let _ = self.flush_acks();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::consumer::{Consumer, ConsumerOpts, Message, MessageFields, StartPosition};
+  use crate::test_helpers::*;
+  use redis::Commands;
+
+  #[test]
+  fn test_iter() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    let mut ids = vec![];
+    for i in 0..5 {
+      ids.push(crate::produce(&mut redis, stream, &[("i", &i.to_string())]).unwrap());
+    }
+
+    let handler = |_id: &str, _message: &Message| Ok(());
+    let opts = ConsumerOpts::default()
+      .group(group_name, consumer_name)
+      .start_pos(StartPosition::StartOfStream)
+      .count(2)
+      .timeout(10);
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+
+    // it works with adapters, acknowledging the entries once the next one is
+    // pulled
+    let values: Vec<String> = consumer
+      .iter()
+      .take(3)
+      .map(|entry| entry.unwrap().message.str("i").unwrap().to_string())
+      .collect();
+    assert_eq!(values, vec!["0", "1", "2"]);
+    assert_eq!(consumer.handled_messages, 2);
+
+    // and yields the last one again with the next iterator, as it may not have
+    // been handled
+    let mut entries = consumer.iter();
+    assert_eq!(entries.next().unwrap().unwrap().message.str("i"), Some("2"));
+    assert_eq!(entries.next().unwrap().unwrap().message.str("i"), Some("3"));
+    drop(entries);
+    assert_eq!(consumer.handled_messages, 3);
+
+    // it only acknowledges the entries acked explicitly, the last one included
+    let entries: Vec<_> = consumer
+      .iter()
+      .manual_ack()
+      .map(|entry| {
+        let entry = entry.unwrap();
+        if entry.message.str("i") == Some("4") {
+          entry.ack();
+        }
+        (entry.id, entry.message)
+      })
+      .collect();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(consumer.handled_messages, 4);
+    let pending: redis::streams::StreamPendingCountReply = redis
+      .xpending_count(stream, group_name, "-", "+", 10)
+      .unwrap();
+    let pending_ids: Vec<String> = pending.ids.into_iter().map(|p| p.id).collect();
+    assert_eq!(pending_ids, vec![ids[3].clone()]);
+
+    // consume handles the messages left buffered by an iterator first
+    ids.push(crate::produce(&mut redis, stream, &[("i", "5")]).unwrap());
+    ids.push(crate::produce(&mut redis, stream, &[("i", "6")]).unwrap());
+    let mut entries = consumer.iter();
+    assert_eq!(entries.next().unwrap().unwrap().message.str("i"), Some("5"));
+    drop(entries);
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 6);
+    let pending: redis::streams::StreamPendingCountReply = redis
+      .xpending_count(stream, group_name, "-", "+", 10)
+      .unwrap();
+    let pending_ids: Vec<String> = pending.ids.into_iter().map(|p| p.id).collect();
+    assert_eq!(pending_ids, vec![ids[3].clone()]);
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+  }
+}
@@ -67,33 +67,77 @@
 //!
 //! - [`ConsumerOpts`](types/struct.ConsumerOpts.html)
 //! - [`Consumer::init`](consumer/struct.Consumer.html#method.init)
+//! - [`Consumer::init_multi`](consumer/struct.Consumer.html#method.init_multi)
 //! - [`Consumer::consume`](consumer/struct.Consumer.html#method.consume)
+//! - [`Consumer::run`](consumer/struct.Consumer.html#method.run)
+//! - [`batch::BatchConsumer`](batch/struct.BatchConsumer.html)
+//! - [`ProducerOpts`](types/struct.ProducerOpts.html)
+//! - [`Producer::produce`](producer/struct.Producer.html#method.produce)
+//! - [`headers::Headers`](headers/struct.Headers.html) and
+//!   [`consumer::with_delivery`](consumer/fn.with_delivery.html)
 //! - [`produce`](fn.produce.html)
-use anyhow::{Context, Result};
-use redis::{Commands, Connection};
+//! - [`connection`](connection/index.html), to own the connection of a
+//!   consumer or producer, or pull it from an `r2d2` pool (requires the `r2d2`
+//!   feature)
+//! - [`sentinel::Sentinel`](sentinel/struct.Sentinel.html), to follow the
+//!   master of a Redis Sentinel deployment
+//! - [`aio::AsyncConsumer`](aio/struct.AsyncConsumer.html) (requires the `aio`
+//!   feature), and [`aio::Pooled`](aio/struct.Pooled.html) for `bb8` pools
+//!   (requires the `bb8` feature)
+//! - [`cluster`](cluster/index.html), for Redis Cluster connections (requires
+//!   the `cluster` feature)
+//! - [`codec::Codec`](codec/trait.Codec.html) (requires the `serde` feature)
+//! - [`typed::TypedConsumer`](typed/type.TypedConsumer.html) (requires the
+//!   `serde` feature)
+use anyhow::Result;
+use redis::ConnectionLike;
 
+use crate::connection::Reconnect;
+
+#[cfg(feature = "aio")]
+pub mod aio;
+pub mod batch;
+pub mod cluster;
+#[cfg(feature = "serde")]
+pub mod codec;
+pub mod compression;
+pub mod connection;
 pub mod consumer;
+pub mod encryption;
+pub mod headers;
+pub mod iter;
+pub mod producer;
+pub mod sentinel;
+#[cfg(feature = "serde")]
+pub mod typed;
 pub mod types;
 
 /// Produces a new message into a Redis stream.
-pub fn produce(
-  redis: &mut Connection,
+///
+/// Shortcut for [`Producer::produce`](producer/struct.Producer.html#method.produce)
+/// with the default options.
+pub fn produce<C: ConnectionLike + Reconnect>(
+  redis: &mut C,
   stream: &str,
   key_values: &[(&str, &str)],
 ) -> Result<String> {
-  let id = redis
-    .xadd::<&str, &str, &str, &str, String>(stream, "*", key_values)
-    .context(format!(
-      "failed to run redis command:\n\
-       XADD {} * {}",
-      stream,
-      key_values
-        .iter()
-        .map(|(k, v)| format!("{} {}", k, v))
-        .collect::<Vec<String>>()
-        .join(" ")
-    ))?;
-  Ok(id)
+  producer::Producer::init(redis, stream, producer::ProducerOpts::default()).produce(key_values)
+}
+
+/// Produces `value` into a Redis stream, mapped to fields according to
+/// `layout`.
+///
+/// Shortcut for [`Producer::produce_typed`](producer/struct.Producer.html#method.produce_typed)
+/// with the default options. Requires the `serde` feature.
+#[cfg(feature = "serde")]
+pub fn produce_typed<T: serde::Serialize, C: ConnectionLike + Reconnect>(
+  redis: &mut C,
+  stream: &str,
+  value: &T,
+  layout: &typed::Layout,
+) -> Result<String> {
+  producer::Producer::init(redis, stream, producer::ProducerOpts::default())
+    .produce_typed(value, layout)
 }
 
 #[cfg(test)]
@@ -102,6 +146,12 @@ pub mod test_helpers {
   use rand::{thread_rng, Rng};
   use redis::{Commands, Connection, RedisResult};
 
+  pub fn delete_group(stream: &str, group: &str) {
+    redis_connection()
+      .xgroup_destroy::<&str, &str, bool>(stream, group)
+      .unwrap();
+  }
+
   pub fn delete_stream(stream: &str) {
     redis_connection().del::<&str, bool>(stream).unwrap();
   }
@@ -120,6 +170,16 @@ pub mod test_helpers {
       .expect("failed to get redis connection")
   }
 
+  #[cfg(feature = "cluster")]
+  pub fn cluster_connection() -> redis::cluster::ClusterConnection {
+    let redis_urls =
+      std::env::var("REDIS_CLUSTER_URLS").unwrap_or_else(|_| "redis://127.0.0.1:7000".to_string());
+    redis::cluster::ClusterClient::open(redis_urls.split(',').collect())
+      .expect("failed to open redis cluster client")
+      .get_connection()
+      .expect("failed to get redis cluster connection")
+  }
+
   pub fn random_string(n: usize) -> String {
     thread_rng()
       .sample_iter(&Alphanumeric)
@@ -133,6 +193,7 @@ pub mod test_helpers {
 mod tests {
   use super::*;
   use crate::test_helpers::*;
+  use anyhow::Context;
   use regex::Regex;
 
   #[test]
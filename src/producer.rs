@@ -0,0 +1,387 @@
+//! Producer adding messages to a stream, with trimming options.
+//!
+//! # Basic usage:
+//!
+//! ```
+//! use redis_stream::producer::{Producer, ProducerOpts};
+//!
+//! let redis_url =
+//!   std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+//!
+//! let mut redis = redis::Client::open(redis_url)
+//!   .expect("client")
+//!   .get_connection()
+//!   .expect("connection");
+//!
+//! // Keep around 1000 messages in the stream
+//! let opts = ProducerOpts::default().maxlen(1000).approximate(true);
+//! let mut producer = Producer::init(&mut redis, "my-stream-4", opts);
+//! producer.produce(&[("temperature", "31")]).expect("produce");
+//!
+//! // Clean up redis
+//! use redis::Commands;
+//! redis.del::<&str, bool>("my-stream-4").expect("del");
+//! ```
+use anyhow::{bail, Context, Result};
+use redis::{Connection, ToRedisArgs};
+use std::time::SystemTime;
+
+pub use super::types::{Backoff, Endpoint, ProducerOpts, Trim};
+pub use crate::compression::Compression;
+use crate::compression::{COMPRESSED_FIELD, COMPRESSION_FIELD};
+use crate::connection::{is_connection_lost, reconnect, AsConnection};
+use crate::encryption::Keyring;
+use crate::headers::Headers;
+
+// A Producer, adding messages to a single stream.
+pub struct Producer<R = Connection>
+where
+  R: AsConnection,
+{
+  pub approximate: bool,
+  pub compression: Option<Compression>,
+  pub compression_threshold: usize,
+  pub create_stream_if_not_exists: bool,
+  pub headers: Option<Headers>,
+  pub keyring: Option<Keyring>,
+  pub limit: Option<usize>,
+  /// Endpoint and backoff to reconnect with when the connection is lost.
+  pub reconnect: Option<(Endpoint, Backoff)>,
+  /// The connection, borrowed, owned or pulled from a pool.
+  pub redis: R,
+  pub stream: String,
+  pub trim: Option<Trim>,
+}
+
+impl<R> Producer<R>
+where
+  R: AsConnection,
+{
+  /// Initializes a new `producer::Producer`.
+  pub fn init(redis: R, stream: &str, opts: ProducerOpts) -> Self {
+    Producer {
+      approximate: opts.approximate,
+      compression: opts.compression,
+      compression_threshold: opts.compression_threshold,
+      create_stream_if_not_exists: opts.create_stream_if_not_exists,
+      headers: opts.headers,
+      keyring: opts.keyring,
+      limit: opts.limit,
+      reconnect: opts.reconnect,
+      redis,
+      stream: stream.to_string(),
+      trim: opts.trim,
+    }
+  }
+
+  /// Produces a new message into the stream, and returns its id.
+  ///
+  /// Field names and values can be anything Redis accepts as arguments,
+  /// including raw bytes (`&[u8]`, `Vec<u8>`, or `&bytes[..]` for
+  /// `bytes::Bytes`), which are written as is.
+  pub fn produce<K, V>(&mut self, key_values: &[(K, V)]) -> Result<String>
+  where
+    K: ToRedisArgs,
+    V: ToRedisArgs,
+  {
+    self.produce_with_id("*", key_values)
+  }
+
+  /// Produces a new message with an explicit `id` into the stream (`*` lets
+  /// Redis generate it), and returns its id.
+  pub fn produce_with_id<K, V>(&mut self, id: &str, key_values: &[(K, V)]) -> Result<String>
+  where
+    K: ToRedisArgs,
+    V: ToRedisArgs,
+  {
+    self.xadd(id, None, key_values)
+  }
+
+  /// Produces a new message with `headers`, overriding the ones of the
+  /// producer, into the stream, and returns its id.
+  pub fn produce_with_headers<K, V>(
+    &mut self,
+    headers: &Headers,
+    key_values: &[(K, V)],
+  ) -> Result<String>
+  where
+    K: ToRedisArgs,
+    V: ToRedisArgs,
+  {
+    self.xadd("*", Some(headers), key_values)
+  }
+
+  fn xadd<K, V>(
+    &mut self,
+    id: &str,
+    headers: Option<&Headers>,
+    key_values: &[(K, V)],
+  ) -> Result<String>
+  where
+    K: ToRedisArgs,
+    V: ToRedisArgs,
+  {
+    // XADD <stream> [NOMKSTREAM] [<MAXLEN|MINID> [=|~] <threshold> [LIMIT <count>]] <id> <key_values...>
+    let mut args = vec![self.stream.clone()];
+    if !self.create_stream_if_not_exists {
+      args.push("NOMKSTREAM".to_string());
+    }
+    if let Some(trim) = &self.trim {
+      match trim {
+        Trim::MaxLen(maxlen) => args.extend(vec!["MAXLEN".to_string(), maxlen.to_string()]),
+        Trim::MinId(minid) => args.extend(vec!["MINID".to_string(), minid.clone()]),
+      }
+      if self.approximate {
+        args.insert(args.len() - 1, "~".to_string());
+        // Redis rejects LIMIT with exact trimming
+        if let Some(limit) = self.limit {
+          args.extend(vec!["LIMIT".to_string(), limit.to_string()]);
+        }
+      }
+    }
+    args.push(id.to_string());
+
+    let mut cmd = redis::cmd("XADD");
+    cmd.arg(&args[..]);
+
+    let mut fields = vec![];
+    let mut compressed = vec![];
+    for (key, value) in key_values {
+      let (key, mut value) = (key.to_redis_args(), value.to_redis_args());
+      args.push(display_arg(&key));
+      args.push(match self.keyring {
+        // Keep encrypted values out of error messages
+        Some(_) => "[encrypted]".to_string(),
+        None => display_arg(&value),
+      });
+      if let Some(compression) = &self.compression {
+        if let Some(name) = self.compressible(&key, &value) {
+          value = vec![compression
+            .compress(&value[0])
+            .context(format!("failed to compress field {}", name))?];
+          compressed.push(name);
+        }
+      }
+      fields.push((key, value));
+    }
+
+    let mut markers = vec![];
+    if let (Some(compression), false) = (&self.compression, compressed.is_empty()) {
+      markers.push((COMPRESSION_FIELD, compression.name().to_string()));
+      markers.push((COMPRESSED_FIELD, compressed.join(",")));
+    }
+    for (key, value) in markers {
+      args.push(key.to_string());
+      args.push(value.clone());
+      fields.push((vec![key.into()], vec![value.into_bytes()]));
+    }
+
+    if let Some(keyring) = &self.keyring {
+      let mut single_fields = vec![];
+      for (key, value) in fields {
+        match (&key[..], &value[..]) {
+          ([key], [value]) => single_fields.push((key.clone(), value.clone())),
+          _ => bail!("encrypted fields must have a single name and value"),
+        }
+      }
+      let encrypted = keyring.encrypt_fields(&self.stream, single_fields)?;
+      // The encryption marker fields come last
+      for (key, value) in &encrypted[encrypted.len() - 4..] {
+        args.push(String::from_utf8_lossy(key).into_owned());
+        args.push(String::from_utf8_lossy(value).into_owned());
+      }
+      fields = encrypted
+        .into_iter()
+        .map(|(key, value)| (vec![key], vec![value]))
+        .collect();
+    }
+
+    for (key, value) in fields {
+      cmd.arg(key).arg(value);
+    }
+
+    // Headers are added last, left readable
+    let headers = match (&self.headers, headers) {
+      (Some(defaults), Some(headers)) => Some(defaults.merge(headers)),
+      (defaults, headers) => defaults.clone().or_else(|| headers.cloned()),
+    };
+    if let Some(mut headers) = headers {
+      headers.produced_at = headers.produced_at.or_else(|| Some(SystemTime::now()));
+      for (key, value) in headers.to_fields() {
+        args.push(key.to_string());
+        args.push(value.clone());
+        cmd.arg(key).arg(value);
+      }
+    }
+
+    let result = match (cmd.query(self.redis.as_connection()), &self.reconnect) {
+      (Err(err), Some((endpoint, backoff))) if is_connection_lost(&err) => {
+        reconnect(self.redis.as_connection(), endpoint, backoff)?;
+        cmd.query(self.redis.as_connection())
+      }
+      (result, _) => result,
+    };
+    let id: Option<String> = result.context(format!(
+      "failed to run redis command:\n\
+       XADD {}",
+      args.join(" ")
+    ))?;
+
+    match id {
+      Some(id) => Ok(id),
+      // NOMKSTREAM on a missing stream
+      None => bail!("stream {} does not exist", self.stream),
+    }
+  }
+
+  /// Returns the name of the field if its value should be compressed: long
+  /// enough, and with a name that can be listed in `_compressed`.
+  fn compressible(&self, key: &[Vec<u8>], value: &[Vec<u8>]) -> Option<String> {
+    match (key, value) {
+      ([key], [value]) if value.len() >= self.compression_threshold => {
+        match std::str::from_utf8(key) {
+          Ok(key) if !key.is_empty() && !key.contains(',') => Some(key.to_string()),
+          _ => None,
+        }
+      }
+      _ => None,
+    }
+  }
+
+  /// Produces `value` into the stream, mapped to fields according to
+  /// `layout`, and returns its id.
+  ///
+  /// Requires the `serde` feature.
+  #[cfg(feature = "serde")]
+  pub fn produce_typed<T>(&mut self, value: &T, layout: &crate::typed::Layout) -> Result<String>
+  where
+    T: serde::Serialize,
+  {
+    let fields = crate::typed::to_fields(value, layout).context(format!(
+      "failed to encode message for stream {}",
+      self.stream
+    ))?;
+    self.produce(&fields)
+  }
+
+  /// Produces `value` encoded with `codec` into the stream (in the `_codec`
+  /// and `_data` fields), and returns its id.
+  ///
+  /// Requires the `serde` feature.
+  #[cfg(feature = "serde")]
+  pub fn produce_encoded<E, T>(&mut self, codec: &E, value: &T) -> Result<String>
+  where
+    E: crate::codec::Codec,
+    T: serde::Serialize,
+  {
+    let fields = crate::codec::encode(codec, value).context(format!(
+      "failed to encode message for stream {} with codec {}",
+      self.stream,
+      codec.name()
+    ))?;
+    self.produce(&fields)
+  }
+}
+
+// Helpers
+
+/// Renders a command argument for error messages (binary data is displayed
+/// lossily).
+fn display_arg(arg: &[Vec<u8>]) -> String {
+  arg
+    .iter()
+    .map(|arg| String::from_utf8_lossy(arg).into_owned())
+    .collect::<Vec<String>>()
+    .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_helpers::*;
+  use redis::Commands;
+
+  #[test]
+  fn test_produce_trim() {
+    let mut redis = redis_connection();
+    let stream = &format!("test-stream-{}", random_string(25));
+
+    // it trims to MAXLEN
+    let opts = ProducerOpts::default().maxlen(2);
+    let mut producer = Producer::init(&mut redis, stream, opts);
+    for id in &["1-1", "2-1", "3-1"] {
+      producer.produce_with_id(id, &[("key", "value")]).unwrap();
+    }
+    let len: usize = redis.xlen(stream).unwrap();
+    assert_eq!(len, 2);
+
+    // it trims to MINID
+    let opts = ProducerOpts::default().minid("4-1");
+    let mut producer = Producer::init(&mut redis, stream, opts);
+    producer
+      .produce_with_id("4-1", &[("key", "value")])
+      .unwrap();
+    let entries: redis::streams::StreamRangeReply = redis.xrange_all(stream).unwrap();
+    let ids: Vec<String> = entries.ids.into_iter().map(|entry| entry.id).collect();
+    assert_eq!(ids, vec!["4-1".to_string()]);
+
+    // it accepts approximate trimming with a LIMIT
+    let opts = ProducerOpts::default()
+      .maxlen(1)
+      .approximate(true)
+      .limit(100);
+    let mut producer = Producer::init(&mut redis, stream, opts);
+    let id = producer.produce(&[("key", "value")]).unwrap();
+    let entries: redis::streams::StreamRangeReply = redis.xrange_all(stream).unwrap();
+    assert_eq!(entries.ids.last().unwrap().id, id);
+
+    // it ignores the LIMIT with exact trimming
+    let opts = ProducerOpts::default().maxlen(1).limit(100);
+    let mut producer = Producer::init(&mut redis, stream, opts);
+    producer.produce(&[("key", "value")]).unwrap();
+    let len: usize = redis.xlen(stream).unwrap();
+    assert_eq!(len, 1);
+
+    // it fails on invalid explicit ids
+    let mut producer = Producer::init(&mut redis, stream, ProducerOpts::default());
+    assert!(producer
+      .produce_with_id("1-1", &[("key", "value")])
+      .is_err());
+
+    delete_stream(stream);
+  }
+
+  #[test]
+  fn test_produce_binary() {
+    use crate::consumer::MessageFields;
+
+    let mut redis = redis_connection();
+    let stream = &format!("test-stream-{}", random_string(25));
+    let payload: Vec<u8> = vec![0, 159, 146, 150, 255];
+
+    let mut producer = Producer::init(&mut redis, stream, ProducerOpts::default());
+    producer.produce(&[("payload", &payload[..])]).unwrap();
+    producer.produce(&[("count", 42)]).unwrap();
+
+    // it keeps the raw bytes
+    let entries: redis::streams::StreamRangeReply = redis.xrange_all(stream).unwrap();
+    assert_eq!(entries.ids[0].map.bytes("payload"), Some(&payload[..]));
+    assert_eq!(entries.ids[0].map.str("payload"), None);
+    assert_eq!(entries.ids[1].map.str("count"), Some("42"));
+    assert_eq!(entries.ids[1].map.bytes("missing"), None);
+
+    delete_stream(stream);
+  }
+
+  #[test]
+  fn test_produce_nomkstream() {
+    let mut redis = redis_connection();
+    let stream = &format!("test-stream-{}", random_string(25));
+
+    let opts = ProducerOpts::default().create_stream_if_not_exists(false);
+    let mut producer = Producer::init(&mut redis, stream, opts);
+    let err = producer.produce(&[("key", "value")]).unwrap_err();
+    assert_eq!(err.to_string(), format!("stream {} does not exist", stream));
+    assert!(!key_exists(&mut redis, stream));
+  }
+}
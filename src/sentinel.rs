@@ -0,0 +1,294 @@
+//! Redis Sentinel support.
+//!
+//! A [`Sentinel`] discovers the current master of a monitored Redis from a
+//! list of sentinels (`SENTINEL get-master-addr-by-name`). Given to
+//! [`ConsumerOpts::reconnect`] or [`ProducerOpts::reconnect`], it is asked for
+//! the new master whenever the connection is lost, or the master was demoted
+//! to a replica (`READONLY` errors) by a failover. Group consumers then resume
+//! from their pending messages on the new master, like after any reconnection.
+//!
+//! The master is reached over TLS when the sentinels are (`rediss://` URLs,
+//! which require the `tls` feature of `redis`), with the same `#insecure`
+//! setting.
+//!
+//! ```no_run
+//! use redis_stream::consumer::{Backoff, Consumer, ConsumerOpts, Message};
+//! use redis_stream::sentinel::Sentinel;
+//!
+//! let sentinel = Sentinel::new(
+//!   "mymaster",
+//!   &["redis://10.0.0.1:26379", "redis://10.0.0.2:26379", "redis://10.0.0.3:26379"],
+//! )
+//! .expect("sentinel");
+//! let mut redis = sentinel.get_connection().expect("connection");
+//!
+//! let handler = |_id: &str, _message: &Message| Ok(());
+//! let opts = ConsumerOpts::default()
+//!   .group("my-group", "worker.1")
+//!   .reconnect(sentinel, Backoff::default());
+//! let mut consumer = Consumer::init(&mut redis, "my-stream", handler, opts).unwrap();
+//! consumer.consume().expect("consume messages");
+//! ```
+//!
+//! [`ConsumerOpts::reconnect`]: ../types/struct.ConsumerOpts.html#method.reconnect
+//! [`ProducerOpts::reconnect`]: ../types/struct.ProducerOpts.html#method.reconnect
+use redis::{
+  Client, Connection, ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo, RedisResult,
+  Value,
+};
+use std::time::Duration;
+
+/// The sentinels monitoring a Redis master, and how to connect to it.
+#[derive(Clone, Debug)]
+pub struct Sentinel {
+  pub connect_timeout: Duration,
+  pub db: i64,
+  pub master_name: String,
+  pub password: Option<String>,
+  pub sentinels: Vec<ConnectionInfo>,
+  pub username: Option<String>,
+}
+
+impl Sentinel {
+  /// Sentinel configuration for the master `master_name`, monitored by the
+  /// `sentinels` (e.g. `redis://10.0.0.1:26379`), asked in this order.
+  pub fn new<T: IntoConnectionInfo + Clone>(
+    master_name: &str,
+    sentinels: &[T],
+  ) -> RedisResult<Self> {
+    let sentinels = sentinels
+      .iter()
+      .cloned()
+      .map(IntoConnectionInfo::into_connection_info)
+      .collect::<RedisResult<Vec<_>>>()?;
+    Ok(Sentinel {
+      connect_timeout: Duration::from_secs(1),
+      db: 0,
+      master_name: master_name.to_string(),
+      password: None,
+      sentinels,
+      username: None,
+    })
+  }
+
+  /// Maximum duration to connect to a sentinel, or to the master, before
+  /// trying the next one (default: 1s).
+  pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+    self.connect_timeout = connect_timeout;
+    self
+  }
+
+  /// Database of the master to select (default: `0`).
+  pub fn db(mut self, db: i64) -> Self {
+    self.db = db;
+    self
+  }
+
+  /// Password of the master.
+  pub fn password(mut self, password: &str) -> Self {
+    self.password = Some(password.to_string());
+    self
+  }
+
+  /// Username of the master (Redis >= 6 ACLs).
+  pub fn username(mut self, username: &str) -> Self {
+    self.username = Some(username.to_string());
+    self
+  }
+
+  /// Asks the sentinels for the address of the master, returning the answer
+  /// of the first one knowing it.
+  pub fn master_addr(&self) -> RedisResult<(String, u16)> {
+    self.find_master().map(|(_, addr)| addr)
+  }
+
+  /// Same as `master_addr`, along with the sentinel which answered.
+  fn find_master(&self) -> RedisResult<(&ConnectionInfo, (String, u16))> {
+    let mut last_err = None;
+    for sentinel in &self.sentinels {
+      let addr = Client::open(sentinel.clone())
+        .and_then(|client| client.get_connection_with_timeout(self.connect_timeout))
+        .and_then(|mut redis| {
+          redis::cmd("SENTINEL")
+            .arg("get-master-addr-by-name")
+            .arg(&self.master_name)
+            .query::<Option<(String, u16)>>(&mut redis)
+        });
+      match addr {
+        Ok(Some(addr)) => return Ok((sentinel, addr)),
+        Ok(None) => {
+          last_err = Some(
+            (
+              ErrorKind::ResponseError,
+              "unknown master",
+              self.master_name.clone(),
+            )
+              .into(),
+          )
+        }
+        Err(err) => last_err = Some(err),
+      }
+    }
+    Err(last_err.unwrap_or_else(|| (ErrorKind::ClientError, "no sentinel configured").into()))
+  }
+
+  /// A client for the current master, over TLS if the sentinel which gave
+  /// its address is.
+  pub fn client(&self) -> RedisResult<Client> {
+    let (sentinel, (host, port)) = self.find_master()?;
+    Client::open(ConnectionInfo {
+      addr: Box::new(master_connection_addr(&sentinel.addr, host, port)),
+      db: self.db,
+      username: self.username.clone(),
+      passwd: self.password.clone(),
+    })
+  }
+
+  /// Opens a connection to the current master, checking that it still is a
+  /// master: sentinels may not have noticed a failover yet.
+  pub fn get_connection(&self) -> RedisResult<Connection> {
+    let mut redis = self
+      .client()?
+      .get_connection_with_timeout(self.connect_timeout)?;
+    let role: Vec<Value> = redis::cmd("ROLE").query(&mut redis)?;
+    match role.first() {
+      Some(Value::Data(role)) if role == b"master" => Ok(redis),
+      _ => Err(
+        (
+          ErrorKind::ReadOnly,
+          "not a master",
+          self.master_name.clone(),
+        )
+          .into(),
+      ),
+    }
+  }
+}
+
+/// Address of the master at `host:port`, reached like the sentinel at
+/// `sentinel`: over TLS (with the same `insecure` setting) or not.
+fn master_connection_addr(sentinel: &ConnectionAddr, host: String, port: u16) -> ConnectionAddr {
+  match sentinel {
+    ConnectionAddr::TcpTls { insecure, .. } => ConnectionAddr::TcpTls {
+      host,
+      port,
+      insecure: *insecure,
+    },
+    _ => ConnectionAddr::Tcp(host, port),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::consumer::{Backoff, Consumer, ConsumerOpts, Message, StartPosition};
+  use crate::producer::{Producer, ProducerOpts};
+  use crate::test_helpers::*;
+  use redis::{Commands, FromRedisValue};
+
+  fn sentinel() -> Sentinel {
+    let urls = std::env::var("REDIS_SENTINEL_URLS").unwrap_or_else(|_| {
+      "redis://127.0.0.1:26379,redis://127.0.0.1:26380,redis://127.0.0.1:26381".to_string()
+    });
+    let urls: Vec<&str> = urls.split(',').collect();
+    Sentinel::new("mymaster", &urls).unwrap()
+  }
+
+  #[test]
+  fn test_no_sentinel() {
+    // it fails without any sentinel to ask
+    let sentinel = Sentinel::new::<&str>("mymaster", &[]).unwrap();
+    assert!(sentinel.master_addr().is_err());
+    assert!(Sentinel::new("mymaster", &["not-a-url"]).is_err());
+  }
+
+  #[test]
+  fn test_master_connection_addr() {
+    // it reaches the master over TLS when the sentinel is
+    let sentinel = ConnectionAddr::TcpTls {
+      host: "10.0.0.1".to_string(),
+      port: 26379,
+      insecure: true,
+    };
+    assert_eq!(
+      master_connection_addr(&sentinel, "10.0.0.2".to_string(), 6379),
+      ConnectionAddr::TcpTls {
+        host: "10.0.0.2".to_string(),
+        port: 6379,
+        insecure: true,
+      }
+    );
+
+    // and over plain TCP otherwise
+    let sentinel = ConnectionAddr::Tcp("10.0.0.1".to_string(), 26379);
+    assert_eq!(
+      master_connection_addr(&sentinel, "10.0.0.2".to_string(), 6379),
+      ConnectionAddr::Tcp("10.0.0.2".to_string(), 6379)
+    );
+  }
+
+  // Requires a local sentinel setup: `make test-sentinel`
+  #[test]
+  #[ignore]
+  fn test_sentinel_failover() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let group_name = &format!("test-group-{}", random_string(25));
+    let sentinel = sentinel();
+    let backoff = Backoff::default().max_attempts(50);
+    let mut redis = sentinel.get_connection().unwrap();
+    let mut redis_c = sentinel.get_connection().unwrap();
+    let master = sentinel.master_addr().unwrap();
+
+    let opts = ProducerOpts::default().reconnect(sentinel.clone(), backoff.clone());
+    let mut producer = Producer::init(&mut redis, stream, opts);
+    producer.produce(&[("i", "0")]).unwrap();
+
+    let mut values = vec![];
+    let handler = |_id: &str, message: &Message| {
+      values.push(String::from_redis_value(&message["i"])?);
+      Ok(())
+    };
+    let opts = ConsumerOpts::default()
+      .group(group_name, "worker.1")
+      .start_pos(StartPosition::StartOfStream)
+      .reconnect(sentinel.clone(), backoff)
+      .timeout(100);
+    let mut consumer = Consumer::init(&mut redis_c, stream, handler, opts).unwrap();
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 1);
+
+    // wait for the replica to catch up, and fail over to it
+    let mut sentinel_redis = Client::open(sentinel.sentinels[0].clone())
+      .unwrap()
+      .get_connection()
+      .unwrap();
+    let _: () = redis::cmd("WAIT")
+      .arg(1)
+      .arg(1_000)
+      .query(producer.redis)
+      .unwrap();
+    let _: () = redis::cmd("SENTINEL")
+      .arg("failover")
+      .arg("mymaster")
+      .query(&mut sentinel_redis)
+      .unwrap();
+    while sentinel.master_addr().unwrap() == master {
+      std::thread::sleep(Duration::from_millis(100));
+    }
+
+    // the producer and the consumer follow the new master, and the consumer
+    // keeps its group position
+    producer.produce(&[("i", "1")]).unwrap();
+    while consumer.handled_messages < 2 {
+      consumer.consume().unwrap();
+    }
+    drop(consumer);
+    assert_eq!(values, vec!["0", "1"]);
+    assert_ne!(sentinel.master_addr().unwrap(), master);
+
+    let mut redis = sentinel.get_connection().unwrap();
+    let pending: redis::streams::StreamPendingReply = redis.xpending(stream, group_name).unwrap();
+    assert_eq!(pending.count(), 0);
+    redis.del::<_, ()>(stream).unwrap();
+  }
+}
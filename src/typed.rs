@@ -0,0 +1,671 @@
+//! Typed messages, encoded and decoded with serde.
+//!
+//! Requires the `serde` feature.
+//!
+//! Messages are mapped to stream fields according to a [`Layout`]:
+//!
+//! - [`Layout::Flatten`] (default): each top-level field of the type is a
+//!   field of the stream entry. Strings are taken as is, numbers and booleans
+//!   use their string representation, `None` fields are left out, and nested
+//!   values (sequences, maps, structs) are JSON encoded.
+//! - [`Layout::Payload`]: the whole message is JSON encoded into a single
+//!   field.
+//!
+//! Messages encoded with a [`codec`](../codec/index.html) are detected and
+//! decoded whatever the layout.
+//!
+//! # Basic usage:
+//!
+//! ```no_run
+//! use redis_stream::consumer::ConsumerOpts;
+//! use redis_stream::typed::TypedConsumer;
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize)]
+//! struct Temperature {
+//!   sensor: String,
+//!   celsius: f64,
+//! }
+//!
+//! let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+//!   .expect("client")
+//!   .get_connection()
+//!   .expect("connection");
+//!
+//! let handler = |_id: &str, temperature: Temperature| {
+//!   println!("{}: {}°C", temperature.sensor, temperature.celsius);
+//!   Ok(())
+//! };
+//! let opts = ConsumerOpts::default().group("my-group", "worker.1");
+//! let mut consumer = TypedConsumer::init_typed(&mut redis, "temperatures", handler, opts).unwrap();
+//! consumer.consume().expect("consume messages");
+//! ```
+//!
+//! # Producing:
+//!
+//! ```no_run
+//! use redis_stream::typed::Layout;
+//! use serde::Serialize;
+//!
+//! #[derive(Serialize)]
+//! struct Temperature {
+//!   sensor: String,
+//!   celsius: f64,
+//! }
+//!
+//! let mut redis = redis::Client::open("redis://127.0.0.1:6379")
+//!   .expect("client")
+//!   .get_connection()
+//!   .expect("connection");
+//!
+//! let temperature = Temperature { sensor: "042".to_string(), celsius: 21.5 };
+//! redis_stream::produce_typed(&mut redis, "temperatures", &temperature, &Layout::Flatten)
+//!   .expect("produce");
+//! ```
+use anyhow::{bail, Result};
+use redis::{Connection, Value};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::{forward_to_deserialize_any, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::marker::PhantomData;
+
+use crate::codec::{is_encoded, Codec, Decoder};
+use crate::consumer::{
+  AsConnection, Consumer, ConsumerOpts, Handler, IntoOutcome, Message, Outcome,
+};
+
+/// A [`Consumer`] decoding messages into `T` before calling its handler.
+pub type TypedConsumer<T, F, R = Connection> = Consumer<TypedHandler<T, F>, R>;
+
+impl<T, F, O, R> TypedConsumer<T, F, R>
+where
+  T: DeserializeOwned,
+  F: FnMut(&str, T) -> Result<O>,
+  O: IntoOutcome,
+  R: AsConnection,
+{
+  /// Initializes a new `typed::TypedConsumer`.
+  ///
+  /// Messages failing to decode are moved to the dead-letter stream, see
+  /// [`TypedHandler::on_decode_error`] to change it.
+  pub fn init_typed(redis: R, stream: &str, handler: F, opts: ConsumerOpts) -> Result<Self> {
+    Consumer::init_multi(redis, &[stream], typed(handler), opts)
+  }
+}
+
+/// Callback for messages failing to decode, see
+/// [`TypedHandler::on_decode_error`].
+pub type DecodeErrorHandler =
+  Box<dyn FnMut(&str, &str, &Message, &DecodeError) -> Result<Outcome> + Send>;
+
+/// A [`Handler`] decoding messages into `T` before passing them to a
+/// `FnMut(id, T)` closure.
+pub struct TypedHandler<T, F> {
+  decoder: Decoder<T>,
+  handler: F,
+  layout: Layout,
+  on_decode_error: DecodeErrorHandler,
+  message_type: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned, F> TypedHandler<T, F> {
+  /// Registers a codec to decode the messages encoded with it (the built-in
+  /// codecs enabled by features are always registered).
+  pub fn codec<C: Codec + Send + 'static>(mut self, codec: C) -> Self {
+    self.decoder.register(codec);
+    self
+  }
+
+  /// Sets how messages are mapped to stream fields (default:
+  /// [`Layout::Flatten`]).
+  pub fn layout(mut self, layout: Layout) -> Self {
+    self.layout = layout;
+    self
+  }
+
+  /// Sets what to do with messages failing to decode: the callback receives
+  /// the stream, the message id, the raw message and the decoding error.
+  ///
+  /// Defaults to [`Outcome::DeadLetter`].
+  pub fn on_decode_error<E>(mut self, on_decode_error: E) -> Self
+  where
+    E: FnMut(&str, &str, &Message, &DecodeError) -> Result<Outcome> + Send + 'static,
+  {
+    self.on_decode_error = Box::new(on_decode_error);
+    self
+  }
+}
+
+impl<T, F, R> Handler for TypedHandler<T, F>
+where
+  T: DeserializeOwned,
+  F: FnMut(&str, T) -> Result<R>,
+  R: IntoOutcome,
+{
+  fn handle(&mut self, stream: &str, id: &str, message: &Message) -> Result<Outcome> {
+    let decoded = if is_encoded(message) {
+      self.decoder.decode(message)
+    } else {
+      from_message_with(message, &self.layout)
+    };
+    match decoded {
+      Ok(message) => (self.handler)(id, message).map(IntoOutcome::into_outcome),
+      Err(err) => (self.on_decode_error)(stream, id, message, &err),
+    }
+  }
+}
+
+/// Wraps a `FnMut(id, T)` closure into a [`Handler`] decoding messages into
+/// `T`.
+pub fn typed<T, F, R>(handler: F) -> TypedHandler<T, F>
+where
+  T: DeserializeOwned,
+  F: FnMut(&str, T) -> Result<R>,
+  R: IntoOutcome,
+{
+  TypedHandler {
+    decoder: Decoder::new(),
+    handler,
+    layout: Layout::Flatten,
+    on_decode_error: Box::new(|_stream, _id, _message, err| {
+      Ok(Outcome::DeadLetter(err.to_string()))
+    }),
+    message_type: PhantomData,
+  }
+}
+
+/// How typed messages are mapped to stream fields.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Layout {
+  /// One stream field per top-level field.
+  #[default]
+  Flatten,
+  /// The whole message JSON encoded into the given field.
+  Payload(String),
+}
+
+/// Decodes the fields of a message into `T`.
+pub fn from_message<T: DeserializeOwned>(message: &Message) -> Result<T, DecodeError> {
+  let fields = message
+    .iter()
+    .map(|(key, value)| (key.as_str(), FieldDeserializer::new(key, value)));
+  T::deserialize(de::value::MapDeserializer::new(fields))
+}
+
+/// Decodes a message produced with the given `layout` into `T`.
+pub fn from_message_with<T: DeserializeOwned>(
+  message: &Message,
+  layout: &Layout,
+) -> Result<T, DecodeError> {
+  match layout {
+    Layout::Flatten => from_message(message),
+    Layout::Payload(field) => match message.get(field) {
+      Some(value) => T::deserialize(FieldDeserializer::new(field, value).json()?)
+        .map_err(|err| DecodeError(format!("field `{}`: {}", field, err))),
+      None => Err(DecodeError(format!("missing field `{}`", field))),
+    },
+  }
+}
+
+/// Encodes `value` into the fields of a message, according to `layout`.
+pub fn to_fields<T: Serialize>(value: &T, layout: &Layout) -> Result<Vec<(String, String)>> {
+  let field = match layout {
+    Layout::Flatten => None,
+    Layout::Payload(field) => Some(field),
+  };
+  let value = serde_json::to_value(value)?;
+  if let Some(field) = field {
+    return Ok(vec![(field.clone(), value.to_string())]);
+  }
+
+  let fields = match value {
+    serde_json::Value::Object(fields) => fields,
+    _ => bail!("only structs and maps can be flattened into stream fields"),
+  };
+  Ok(
+    fields
+      .into_iter()
+      .filter_map(|(key, value)| match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(value) => Some((key, value)),
+        value => Some((key, value.to_string())),
+      })
+      .collect(),
+  )
+}
+
+/// Error returned when a message can't be decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeError(String);
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "failed to decode message: {}", self.0)
+  }
+}
+
+impl DecodeError {
+  pub(crate) fn new(message: String) -> Self {
+    DecodeError(message)
+  }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl de::Error for DecodeError {
+  fn custom<M: fmt::Display>(msg: M) -> Self {
+    DecodeError(msg.to_string())
+  }
+}
+
+// Deserializes a single field value, parsing it according to the type
+// requested.
+struct FieldDeserializer<'a> {
+  key: &'a str,
+  value: Option<Cow<'a, [u8]>>,
+}
+
+impl<'a> FieldDeserializer<'a> {
+  fn new(key: &'a str, value: &'a Value) -> Self {
+    let value = match value {
+      Value::Data(data) => Some(Cow::Borrowed(&data[..])),
+      Value::Status(status) => Some(Cow::Borrowed(status.as_bytes())),
+      Value::Int(int) => Some(Cow::Owned(int.to_string().into_bytes())),
+      Value::Okay => Some(Cow::Borrowed(&b"OK"[..])),
+      _ => None,
+    };
+    FieldDeserializer { key, value }
+  }
+
+  fn as_str(&self) -> Result<&str, DecodeError> {
+    match &self.value {
+      Some(value) => std::str::from_utf8(value)
+        .map_err(|_| DecodeError(format!("field `{}` is not valid UTF-8", self.key))),
+      None => Err(DecodeError(format!("field `{}` has no value", self.key))),
+    }
+  }
+
+  fn parse<P: std::str::FromStr>(&self, type_name: &str) -> Result<P, DecodeError> {
+    let value = self.as_str()?;
+    value.parse().map_err(|_| {
+      DecodeError(format!(
+        "field `{}`: invalid {} `{}`",
+        self.key, type_name, value
+      ))
+    })
+  }
+
+  fn json(&self) -> Result<serde_json::Value, DecodeError> {
+    match &self.value {
+      Some(value) => serde_json::from_slice(value).map_err(|err| self.json_error(err)),
+      None => Err(DecodeError(format!("field `{}` has no value", self.key))),
+    }
+  }
+
+  fn json_error(&self, err: serde_json::Error) -> DecodeError {
+    DecodeError(format!("field `{}`: {}", self.key, err))
+  }
+}
+
+macro_rules! deserialize_parsed {
+  ($($method:ident => $visit:ident: $ty:ty,)*) => {
+    $(
+      fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+        visitor.$visit(self.parse::<$ty>(stringify!($ty))?)
+      }
+    )*
+  };
+}
+
+macro_rules! deserialize_json {
+  ($($method:ident($($arg:ident: $ty:ty),*),)*) => {
+    $(
+      fn $method<V: Visitor<'de>>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, DecodeError> {
+        self
+          .json()?
+          .$method($($arg,)* visitor)
+          .map_err(|err| self.json_error(err))
+      }
+    )*
+  };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for FieldDeserializer<'a> {
+  type Error = DecodeError;
+
+  fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+    match &self.value {
+      Some(value) => match std::str::from_utf8(value) {
+        Ok(value) => visitor.visit_str(value),
+        Err(_) => visitor.visit_bytes(value),
+      },
+      None => visitor.visit_unit(),
+    }
+  }
+
+  deserialize_parsed! {
+    deserialize_bool => visit_bool: bool,
+    deserialize_i8 => visit_i8: i8,
+    deserialize_i16 => visit_i16: i16,
+    deserialize_i32 => visit_i32: i32,
+    deserialize_i64 => visit_i64: i64,
+    deserialize_u8 => visit_u8: u8,
+    deserialize_u16 => visit_u16: u16,
+    deserialize_u32 => visit_u32: u32,
+    deserialize_u64 => visit_u64: u64,
+    deserialize_f32 => visit_f32: f32,
+    deserialize_f64 => visit_f64: f64,
+    deserialize_char => visit_char: char,
+  }
+
+  fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+    visitor.visit_str(self.as_str()?)
+  }
+
+  fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+    self.deserialize_str(visitor)
+  }
+
+  fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+    match &self.value {
+      Some(value) => visitor.visit_bytes(value),
+      None => visitor.visit_bytes(&[]),
+    }
+  }
+
+  fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+    self.deserialize_bytes(visitor)
+  }
+
+  fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+    match &self.value {
+      Some(_) => visitor.visit_some(self),
+      None => visitor.visit_none(),
+    }
+  }
+
+  fn deserialize_newtype_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    visitor: V,
+  ) -> Result<V::Value, DecodeError> {
+    visitor.visit_newtype_struct(self)
+  }
+
+  fn deserialize_enum<V: Visitor<'de>>(
+    self,
+    name: &'static str,
+    variants: &'static [&'static str],
+    visitor: V,
+  ) -> Result<V::Value, DecodeError> {
+    let value = self.as_str()?;
+    if value.starts_with('{') || value.starts_with('"') {
+      // JSON encoded variant
+      self
+        .json()?
+        .deserialize_enum(name, variants, visitor)
+        .map_err(|err| self.json_error(err))
+    } else {
+      // Unit variant
+      visitor.visit_enum(value.into_deserializer())
+    }
+  }
+
+  deserialize_json! {
+    deserialize_seq(),
+    deserialize_tuple(len: usize),
+    deserialize_tuple_struct(name: &'static str, len: usize),
+    deserialize_map(),
+    deserialize_struct(name: &'static str, fields: &'static [&'static str]),
+  }
+
+  forward_to_deserialize_any! {
+    i128 u128 unit unit_struct identifier ignored_any
+  }
+}
+
+impl<'de, 'a> IntoDeserializer<'de, DecodeError> for FieldDeserializer<'a> {
+  type Deserializer = Self;
+
+  fn into_deserializer(self) -> Self {
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::consumer::StartPosition;
+  use crate::test_helpers::*;
+  use redis::Commands;
+  use serde::Deserialize;
+  use std::collections::HashMap;
+
+  #[derive(Debug, Deserialize, PartialEq, Serialize)]
+  enum Unit {
+    Celsius,
+    Fahrenheit,
+  }
+
+  #[derive(Debug, Deserialize, PartialEq, Serialize)]
+  struct Temperature {
+    sensor: String,
+    value: f64,
+    unit: Unit,
+    calibrated: bool,
+    tags: Vec<String>,
+    location: Option<String>,
+  }
+
+  fn message(fields: &[(&str, &str)]) -> Message {
+    fields
+      .iter()
+      .map(|(k, v)| (k.to_string(), Value::Data(v.as_bytes().to_vec())))
+      .collect()
+  }
+
+  #[test]
+  fn test_from_message() {
+    let temperature: Temperature = from_message(&message(&[
+      ("sensor", "042"),
+      ("value", "21.5"),
+      ("unit", "Celsius"),
+      ("calibrated", "true"),
+      ("tags", r#"["indoor","kitchen"]"#),
+    ]))
+    .unwrap();
+    assert_eq!(
+      temperature,
+      Temperature {
+        sensor: "042".to_string(),
+        value: 21.5,
+        unit: Unit::Celsius,
+        calibrated: true,
+        tags: vec!["indoor".to_string(), "kitchen".to_string()],
+        location: None,
+      }
+    );
+
+    // it decodes into maps
+    let map: HashMap<String, u32> = from_message(&message(&[("a", "1"), ("b", "2")])).unwrap();
+    assert_eq!(map.get("b"), Some(&2));
+
+    // it fails on invalid or missing fields
+    let err = from_message::<Temperature>(&message(&[
+      ("sensor", "042"),
+      ("value", "hot"),
+      ("unit", "Celsius"),
+      ("calibrated", "true"),
+      ("tags", "[]"),
+    ]))
+    .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "failed to decode message: field `value`: invalid f64 `hot`"
+    );
+    assert!(from_message::<Temperature>(&message(&[("sensor", "042")])).is_err());
+  }
+
+  fn temperature() -> Temperature {
+    Temperature {
+      sensor: "042".to_string(),
+      value: 21.5,
+      unit: Unit::Celsius,
+      calibrated: true,
+      tags: vec!["indoor".to_string()],
+      location: None,
+    }
+  }
+
+  #[test]
+  fn test_to_fields() {
+    let mut fields = to_fields(&temperature(), &Layout::Flatten).unwrap();
+    fields.sort();
+    let expected: Vec<(String, String)> = vec![
+      ("calibrated", "true"),
+      ("sensor", "042"),
+      ("tags", r#"["indoor"]"#),
+      ("unit", "Celsius"),
+      ("value", "21.5"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+    assert_eq!(fields, expected);
+
+    let fields = to_fields(&temperature(), &Layout::Payload("payload".to_string())).unwrap();
+    assert_eq!(fields.len(), 1);
+    assert_eq!(fields[0].0, "payload");
+
+    // it can only flatten structs and maps
+    assert!(to_fields(&42, &Layout::Flatten).is_err());
+  }
+
+  #[test]
+  fn test_produce_typed() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    // it round-trips with both layouts
+    for layout in &[Layout::Flatten, Layout::Payload("payload".to_string())] {
+      crate::produce_typed(&mut redis, stream, &temperature(), layout).unwrap();
+
+      let (tx, rx) = std::sync::mpsc::channel();
+      let handler = typed(move |_id: &str, temperature: Temperature| {
+        tx.send(temperature)?;
+        Ok(())
+      })
+      .layout(layout.clone());
+      let opts = ConsumerOpts::default()
+        .start_pos(StartPosition::StartOfStream)
+        .count(1)
+        .timeout(10);
+      let mut consumer = Consumer::init_multi(&mut redis_c, &[stream], handler, opts).unwrap();
+      consumer.consume().unwrap();
+      assert_eq!(rx.try_recv().unwrap(), temperature());
+
+      delete_stream(stream);
+    }
+  }
+
+  #[test]
+  fn test_send() {
+    fn assert_send<T: Send>() {}
+
+    // an owned typed consumer can be moved into a thread
+    assert_send::<TypedConsumer<Temperature, fn(&str, Temperature) -> Result<()>>>();
+  }
+
+  #[test]
+  fn test_consume() {
+    let group_name = &format!("test-group-{}", random_string(25));
+    let consumer_name = &format!("test-consumer-{}", random_string(25));
+    let stream = &format!("test-stream-{}", random_string(25));
+    let dead_letter_stream = &format!("{}:dead-letter", stream);
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    crate::produce(
+      &mut redis,
+      stream,
+      &[
+        ("sensor", "042"),
+        ("value", "70"),
+        ("unit", "Fahrenheit"),
+        ("calibrated", "false"),
+        ("tags", "[]"),
+        ("location", "garage"),
+      ],
+    )
+    .unwrap();
+    let invalid_id = crate::produce(&mut redis, stream, &[("sensor", "043")]).unwrap();
+
+    let mut temperatures = vec![];
+    let handler = |_id: &str, temperature: Temperature| {
+      temperatures.push(temperature);
+      Ok(())
+    };
+    let opts = ConsumerOpts::default()
+      .group(group_name, consumer_name)
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(10);
+    let mut consumer = TypedConsumer::init_typed(&mut redis_c, stream, handler, opts).unwrap();
+
+    // it decodes messages, and dead-letters the invalid ones
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 1);
+    drop(consumer);
+    assert_eq!(temperatures.len(), 1);
+    assert_eq!(temperatures[0].unit, Unit::Fahrenheit);
+    assert_eq!(temperatures[0].location, Some("garage".to_string()));
+
+    let pending: redis::streams::StreamPendingReply = redis.xpending(stream, group_name).unwrap();
+    assert_eq!(pending.count(), 0);
+    let dead_letters: redis::streams::StreamRangeReply =
+      redis.xrange_all(dead_letter_stream).unwrap();
+    assert_eq!(dead_letters.ids.len(), 1);
+    assert_eq!(
+      dead_letters.ids[0].get::<String>("_dlq_id").unwrap(),
+      invalid_id
+    );
+
+    delete_group(stream, group_name);
+    delete_stream(stream);
+    delete_stream(dead_letter_stream);
+  }
+
+  #[test]
+  fn test_on_decode_error() {
+    let stream = &format!("test-stream-{}", random_string(25));
+    let mut redis = redis_connection();
+    let mut redis_c = redis_connection();
+
+    crate::produce(&mut redis, stream, &[("sensor", "043")]).unwrap();
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let handler = typed(|_id: &str, _temperature: Temperature| Ok(())).on_decode_error(
+      move |_stream, id, _message, err| {
+        tx.send((id.to_string(), err.clone()))?;
+        Ok(Outcome::Skip)
+      },
+    );
+    let opts = ConsumerOpts::default()
+      .start_pos(StartPosition::StartOfStream)
+      .timeout(10);
+    let mut consumer = Consumer::init_multi(&mut redis_c, &[stream], handler, opts).unwrap();
+
+    // it calls the decode error handler
+    consumer.consume().unwrap();
+    assert_eq!(consumer.handled_messages, 0);
+    let (_id, err) = rx.try_recv().unwrap();
+    assert_eq!(
+      err.to_string(),
+      "failed to decode message: missing field `value`"
+    );
+    assert!(!key_exists(&mut redis, &format!("{}:dead-letter", stream)));
+
+    delete_stream(stream);
+  }
+}
@@ -1,4 +1,11 @@
-//! Defines types to use with the consumer commands.
+//! Defines types to use with the consumer and producer commands.
+
+use std::time::Duration;
+
+use crate::compression::Compression;
+use crate::encryption::Keyring;
+use crate::headers::Headers;
+use crate::sentinel::Sentinel;
 
 #[derive(Clone, Debug)]
 pub enum StartPosition {
@@ -7,6 +14,60 @@ pub enum StartPosition {
   StartOfStream,
 }
 
+/// What a [`Consumer`] should do with a message once handled.
+///
+/// [`Consumer`]: ../consumer/struct.Consumer.html
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+  /// The message was handled: acknowledge it.
+  Ack,
+  /// Move the message to the dead-letter stream, with the given reason, and
+  /// acknowledge it.
+  DeadLetter(String),
+  /// Leave the message pending so it gets delivered again, once claimed (see
+  /// `ConsumerOpts::claim_idle`), and moved to the dead-letter stream once
+  /// `max_deliveries` is reached.
+  Retry,
+  /// Acknowledge the message without counting it as handled.
+  Skip,
+  /// Leave the message pending and stop consuming: the rest of the batch is
+  /// not handled, and `Consumer::run` returns. The message and the rest of
+  /// the batch are handled first by the next `Consumer::consume` (or
+  /// `Consumer::run`).
+  Stop,
+}
+
+/// Conversion of a handler's return value into an [`Outcome`]: `()` means
+/// [`Outcome::Ack`].
+pub trait IntoOutcome {
+  fn into_outcome(self) -> Outcome;
+}
+
+impl IntoOutcome for () {
+  fn into_outcome(self) -> Outcome {
+    Outcome::Ack
+  }
+}
+
+impl IntoOutcome for Outcome {
+  fn into_outcome(self) -> Outcome {
+    self
+  }
+}
+
+/// Where a message handed to a [`ContextHandler`] comes from.
+///
+/// [`ContextHandler`]: ../consumer/trait.ContextHandler.html
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+  /// Claimed from another consumer of the group, after being left idle.
+  Claimed,
+  /// New data, delivered for the first time.
+  New,
+  /// Left pending by a previous run of this consumer.
+  Pending,
+}
+
 /// Builder options for [`Consumer::init`].
 ///
 /// Configuration settings for stream consumers (simple or group).
@@ -34,12 +95,17 @@ pub enum StartPosition {
 /// ```
 /// [`Consumer`]: ../consumer/struct.Consumer.html
 /// [`Consumer::init`]:../consumer/struct.Consumer.html#method.init
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ConsumerOpts {
+  pub claim_idle: Option<usize>,
   pub count: Option<usize>,
   pub create_stream_if_not_exists: bool,
+  pub dead_letter_stream: Option<String>,
   pub group: Option<(String, String)>,
+  pub keyring: Option<Keyring>,
+  pub max_deliveries: Option<usize>,
   pub process_pending: bool,
+  pub reconnect: Option<(Endpoint, Backoff)>,
   pub start_pos: StartPosition,
   pub timeout: usize,
 }
@@ -47,10 +113,15 @@ pub struct ConsumerOpts {
 impl Default for ConsumerOpts {
   fn default() -> Self {
     Self {
+      claim_idle: None,
       count: None,
       create_stream_if_not_exists: true,
+      dead_letter_stream: None,
       group: None,
+      keyring: None,
+      max_deliveries: None,
       process_pending: true,
+      reconnect: None,
       start_pos: StartPosition::EndOfStream,
       timeout: 2_000,
     }
@@ -58,7 +129,20 @@ impl Default for ConsumerOpts {
 }
 
 impl ConsumerOpts {
-  /// Maximum number of message to read from the stream in one batch
+  /// Periodically claim the messages the consumers of the group left pending
+  /// for at least `min_idle` ms, and handle them like new ones (group
+  /// consumers only). This includes the messages of the consumer itself, like
+  /// the ones it failed to handle.
+  ///
+  /// Uses `XAUTOCLAIM`, or `XPENDING` + `XCLAIM` on Redis versions without it.
+  pub fn claim_idle(mut self, min_idle: usize) -> Self {
+    self.claim_idle = Some(min_idle);
+    self
+  }
+
+  /// Maximum number of message to read from the stream in one batch (`COUNT`
+  /// option of `XREAD`/`XREADGROUP`), also used when draining pending
+  /// messages. Unbounded by default.
   pub fn count(mut self, count: usize) -> Self {
     self.count = Some(count);
     self
@@ -70,6 +154,13 @@ impl ConsumerOpts {
     self
   }
 
+  /// Stream receiving the messages delivered more than `max_deliveries` times
+  /// (default: `<stream>:dead-letter`).
+  pub fn dead_letter_stream(mut self, dead_letter_stream: &str) -> Self {
+    self.dead_letter_stream = Some(dead_letter_stream.to_string());
+    self
+  }
+
   /// Name of the group and consumer. Enables Redis group consumer behavior if
   /// specified
   pub fn group(mut self, group_name: &str, consumer_name: &str) -> Self {
@@ -77,6 +168,29 @@ impl ConsumerOpts {
     self
   }
 
+  /// Keys to decrypt encrypted messages with (see
+  /// [`encryption`](../encryption/index.html)).
+  pub fn keyring(mut self, keyring: Keyring) -> Self {
+    self.keyring = Some(keyring);
+    self
+  }
+
+  /// Maximum number of deliveries of a message the handler keeps failing on,
+  /// before moving it to the dead-letter stream and acknowledging it (group
+  /// consumers only).
+  ///
+  /// A failed message is left pending, and a consumer reading new messages
+  /// (`>`) doesn't read its own pending messages again: combine it with
+  /// [`claim_idle`](#method.claim_idle) to deliver them again (or restart the
+  /// consumer with `process_pending`), or they are never dead-lettered.
+  ///
+  /// The dead-letter entry holds the original fields along with
+  /// `_dlq_id`, `_dlq_stream`, `_dlq_group` and `_dlq_error`.
+  pub fn max_deliveries(mut self, max_deliveries: usize) -> Self {
+    self.max_deliveries = Some(max_deliveries);
+    self
+  }
+
   /// Start by processing pending messages before switching to real time data
   /// (default: `true`)
   pub fn process_pending(mut self, process_pending: bool) -> Self {
@@ -84,6 +198,19 @@ impl ConsumerOpts {
     self
   }
 
+  /// Reconnect to `endpoint` (a `redis::Client` or a [`Sentinel`]) when the
+  /// connection is lost, waiting between attempts according to `backoff`.
+  ///
+  /// The consumer then resumes from its last position, or from its pending
+  /// messages in a group (which also gets created again if needed), so no
+  /// message is lost or skipped.
+  ///
+  /// [`Sentinel`]: ../sentinel/struct.Sentinel.html
+  pub fn reconnect<E: Into<Endpoint>>(mut self, endpoint: E, backoff: Backoff) -> Self {
+    self.reconnect = Some((endpoint.into(), backoff));
+    self
+  }
+
   /// Where to start reading messages in the stream.
   pub fn start_pos(mut self, start_pos: StartPosition) -> Self {
     self.start_pos = start_pos;
@@ -96,3 +223,317 @@ impl ConsumerOpts {
     self
   }
 }
+
+/// Where to reconnect to, see [`ConsumerOpts::reconnect`] and
+/// [`ProducerOpts::reconnect`].
+///
+/// [`ConsumerOpts::reconnect`]: struct.ConsumerOpts.html#method.reconnect
+/// [`ProducerOpts::reconnect`]: struct.ProducerOpts.html#method.reconnect
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+  /// A single Redis server.
+  Client(redis::Client),
+  /// The current master of a Redis Sentinel deployment (see
+  /// [`sentinel`](../sentinel/index.html)).
+  Sentinel(Sentinel),
+}
+
+impl Endpoint {
+  /// Opens a connection to the endpoint.
+  pub fn get_connection(&self) -> redis::RedisResult<redis::Connection> {
+    match self {
+      Endpoint::Client(client) => client.get_connection(),
+      Endpoint::Sentinel(sentinel) => sentinel.get_connection(),
+    }
+  }
+}
+
+impl From<redis::Client> for Endpoint {
+  fn from(client: redis::Client) -> Self {
+    Endpoint::Client(client)
+  }
+}
+
+impl From<Sentinel> for Endpoint {
+  fn from(sentinel: Sentinel) -> Self {
+    Endpoint::Sentinel(sentinel)
+  }
+}
+
+/// Exponential backoff between reconnection attempts, see
+/// [`ConsumerOpts::reconnect`].
+///
+/// The delay before attempt `n` (from 1) is `initial * multiplier^(n - 1)`,
+/// capped at `max`, minus a random part of up to `jitter` of it.
+/// Attempts go on forever by default, blocking the consumer or the producer
+/// until Redis is back: set `max_attempts` to fail instead.
+///
+/// ```
+/// use redis_stream::types::Backoff;
+/// use std::time::Duration;
+///
+/// let backoff = Backoff::default()
+///   .initial(Duration::from_millis(50))
+///   .max_attempts(10);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backoff {
+  pub initial: Duration,
+  pub jitter: f64,
+  pub max: Duration,
+  pub max_attempts: Option<usize>,
+  pub multiplier: f64,
+}
+
+impl Default for Backoff {
+  fn default() -> Self {
+    Self {
+      initial: Duration::from_millis(100),
+      jitter: 0.5,
+      max: Duration::from_secs(30),
+      max_attempts: None,
+      multiplier: 2.0,
+    }
+  }
+}
+
+impl Backoff {
+  /// Delay before the first attempt (default: 100ms).
+  pub fn initial(mut self, initial: Duration) -> Self {
+    self.initial = initial;
+    self
+  }
+
+  /// Part of the delay randomly removed, from `0.0` to `1.0`, so that
+  /// consumers don't all reconnect at once (default: `0.5`). Clamped to that
+  /// range, NaN meaning no jitter.
+  pub fn jitter(mut self, jitter: f64) -> Self {
+    self.jitter = clamp_jitter(jitter);
+    self
+  }
+
+  /// Maximum delay between two attempts (default: 30s).
+  pub fn max(mut self, max: Duration) -> Self {
+    self.max = max;
+    self
+  }
+
+  /// Give up after this many attempts (default: never, so a consumer or a
+  /// producer blocks until Redis is reachable again).
+  pub fn max_attempts(mut self, max_attempts: usize) -> Self {
+    self.max_attempts = Some(max_attempts);
+    self
+  }
+
+  /// Factor applied to the delay after each attempt (default: `2.0`). Negative
+  /// and NaN factors are replaced by `0.0`.
+  pub fn multiplier(mut self, multiplier: f64) -> Self {
+    self.multiplier = multiplier.max(0.0);
+    self
+  }
+
+  /// Delay before the attempt `attempt` (from 1), jitter included, never more
+  /// than `max`.
+  pub fn delay(&self, attempt: usize) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(i32::MAX as usize) as i32;
+    let delay = self.initial.as_secs_f64() * self.multiplier.max(0.0).powi(exponent);
+    // `min` ignores NaN (a zero initial delay times an infinite multiplier)
+    let delay = delay.min(self.max.as_secs_f64());
+    let jitter = clamp_jitter(self.jitter) * rand::random::<f64>();
+    Duration::try_from_secs_f64(delay * (1.0 - jitter)).unwrap_or(self.max)
+  }
+}
+
+// `f64::clamp` keeps NaN.
+fn clamp_jitter(jitter: f64) -> f64 {
+  if jitter.is_nan() {
+    0.0
+  } else {
+    jitter.clamp(0.0, 1.0)
+  }
+}
+
+/// Trimming strategy applied by a [`Producer`] on each `XADD`.
+///
+/// [`Producer`]: ../producer/struct.Producer.html
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trim {
+  /// Keep at most this many entries (`MAXLEN`).
+  MaxLen(usize),
+  /// Evict the entries with an id lower than this one (`MINID`).
+  MinId(String),
+}
+
+/// Builder options for [`Producer::init`].
+///
+/// Configuration settings for stream producers.
+///
+/// ```
+/// use redis_stream::producer::ProducerOpts;
+///
+/// // XADD <stream> MAXLEN ~ 10000 * <key_values...>
+/// let opts = ProducerOpts::default().maxlen(10_000).approximate(true);
+/// ```
+///
+/// [`Producer::init`]: ../producer/struct.Producer.html#method.init
+#[derive(Clone, Debug)]
+pub struct ProducerOpts {
+  pub approximate: bool,
+  pub compression: Option<Compression>,
+  pub compression_threshold: usize,
+  pub create_stream_if_not_exists: bool,
+  pub headers: Option<Headers>,
+  pub keyring: Option<Keyring>,
+  pub limit: Option<usize>,
+  pub reconnect: Option<(Endpoint, Backoff)>,
+  pub trim: Option<Trim>,
+}
+
+impl Default for ProducerOpts {
+  fn default() -> Self {
+    Self {
+      approximate: false,
+      compression: None,
+      compression_threshold: 1024,
+      create_stream_if_not_exists: true,
+      headers: None,
+      keyring: None,
+      limit: None,
+      reconnect: None,
+      trim: None,
+    }
+  }
+}
+
+impl ProducerOpts {
+  /// Trim approximately (`~`), which is much more efficient than exact
+  /// trimming but can leave a few extra entries (default: `false`).
+  pub fn approximate(mut self, approximate: bool) -> Self {
+    self.approximate = approximate;
+    self
+  }
+
+  /// Compress the field values at least `compression_threshold` bytes long with
+  /// `compression` (see [`compression`](../compression/index.html)).
+  pub fn compression(mut self, compression: Compression) -> Self {
+    self.compression = Some(compression);
+    self
+  }
+
+  /// Minimum size in bytes of the field values to compress (default: `1024`).
+  pub fn compression_threshold(mut self, compression_threshold: usize) -> Self {
+    self.compression_threshold = compression_threshold;
+    self
+  }
+
+  /// Create the stream if it doesn't exist yet (default: `true`). When
+  /// `false`, adds `NOMKSTREAM` and producing to a missing stream fails.
+  pub fn create_stream_if_not_exists(mut self, create_stream_if_not_exists: bool) -> Self {
+    self.create_stream_if_not_exists = create_stream_if_not_exists;
+    self
+  }
+
+  /// Headers of every message, overridden by the ones passed to
+  /// `produce_with_headers` (see [`headers`](../headers/index.html)).
+  pub fn headers(mut self, headers: Headers) -> Self {
+    self.headers = Some(headers);
+    self
+  }
+
+  /// Encrypt the field values with the primary key of `keyring` (see
+  /// [`encryption`](../encryption/index.html)).
+  pub fn keyring(mut self, keyring: Keyring) -> Self {
+    self.keyring = Some(keyring);
+    self
+  }
+
+  /// Maximum number of entries evicted by each `XADD` (`LIMIT`), with
+  /// approximate trimming only: ignored with exact trimming, which Redis
+  /// doesn't allow to limit.
+  pub fn limit(mut self, limit: usize) -> Self {
+    self.limit = Some(limit);
+    self
+  }
+
+  /// Trim the stream to `maxlen` entries.
+  pub fn maxlen(mut self, maxlen: usize) -> Self {
+    self.trim = Some(Trim::MaxLen(maxlen));
+    self
+  }
+
+  /// Trim the entries with an id lower than `minid`.
+  pub fn minid(mut self, minid: &str) -> Self {
+    self.trim = Some(Trim::MinId(minid.to_string()));
+    self
+  }
+
+  /// Reconnect to `endpoint` (a `redis::Client` or a [`Sentinel`]) when the
+  /// connection is lost, waiting between attempts according to `backoff`,
+  /// and send the interrupted `XADD` again.
+  ///
+  /// Messages are then produced at least once: if the connection was lost
+  /// after Redis added the message, a message with an automatic id (`*`) is
+  /// added twice under two ids, so consumers should be idempotent. With an
+  /// explicit id (see `Producer::produce_with_id`), the second `XADD` fails
+  /// instead, as the id is no longer greater than the last one of the stream.
+  ///
+  /// [`Sentinel`]: ../sentinel/struct.Sentinel.html
+  pub fn reconnect<E: Into<Endpoint>>(mut self, endpoint: E, backoff: Backoff) -> Self {
+    self.reconnect = Some((endpoint.into(), backoff));
+    self
+  }
+}
+
+/// Summary returned by [`Consumer::run`] once it has been shut down.
+///
+/// [`Consumer::run`]: ../consumer/struct.Consumer.html#method.run
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RunSummary {
+  /// Number of batches read from the stream (one `XREAD`/`XREADGROUP` round
+  /// each).
+  pub batches: u64,
+  /// Total time spent in the run loop.
+  pub elapsed: Duration,
+  /// Number of messages the handler returned an error for during the run.
+  pub failed_messages: u32,
+  /// Number of messages handled (and acknowledged for group consumers) during
+  /// the run.
+  pub handled_messages: u32,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_backoff_delay() {
+    let backoff = Backoff::default()
+      .initial(Duration::from_millis(100))
+      .max(Duration::from_secs(1))
+      .jitter(0.0);
+    assert_eq!(backoff.delay(1), Duration::from_millis(100));
+    assert_eq!(backoff.delay(2), Duration::from_millis(200));
+    assert_eq!(backoff.delay(10), Duration::from_secs(1));
+
+    // it clamps invalid multipliers and jitters instead of panicking
+    for multiplier in &[-2.0, f64::NAN, f64::INFINITY] {
+      for jitter in &[-1.0, 2.0, f64::NAN] {
+        let backoff = backoff.clone().multiplier(*multiplier).jitter(*jitter);
+        assert!(backoff.delay(2) <= Duration::from_secs(1));
+      }
+    }
+    let backoff = Backoff {
+      initial: Duration::ZERO,
+      jitter: f64::NAN,
+      multiplier: f64::INFINITY,
+      ..backoff
+    };
+    assert!(backoff.delay(2) <= Duration::from_secs(1));
+    let backoff = Backoff {
+      max: Duration::MAX,
+      multiplier: 1e300,
+      ..backoff
+    };
+    backoff.delay(i32::MAX as usize);
+  }
+}